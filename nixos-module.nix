@@ -32,6 +32,31 @@ in
       '';
     };
 
+    listen = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "unix:/run/cache-signing-server/socket";
+      description = ''
+        Where to listen instead of `host` and `port`, as `<ip>:<port>` or
+        `unix:<path>`. Callers on a Unix socket are identified by their uid
+        and primary gid, which `authFile` rules can match with `"uid": <uid>`
+        or `"gid": <gid>`.
+
+        The service gets a `/run/cache-signing-server` directory to put its
+        socket in.
+      '';
+    };
+
+    socketActivation = mkOption {
+      type = types.bool;
+      default = false;
+      description = ''
+        Have systemd open the socket from `listen`, or `host` and `port`, and
+        start the service on the first connection. The service then runs
+        without network access of its own.
+      '';
+    };
+
     secretKeyFile = mkOption {
       type = types.str;
       description = ''
@@ -43,6 +68,325 @@ in
       '';
     };
 
+    extraSecretKeyFiles = mkOption {
+      type = types.listOf types.str;
+      default = [ ];
+      description = ''
+        Additional files holding secret cache signing keys to load into the
+        keyring. Requests select a key by name with the `key` query parameter.
+      '';
+    };
+
+    primaryKey = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      description = ''
+        The name of the key used when a request doesn't select one. Defaults
+        to the key in `secretKeyFile`.
+      '';
+    };
+
+    keyStatus = mkOption {
+      type = types.attrsOf (types.enum [ "active" "signing-only" "retired" ]);
+      default = { };
+      example = { "cache.example.org-1" = "retired"; "cache.example.org-2" = "active"; };
+      description = ''
+        The rotation status of keys in the keyring, by key name. Keys default
+        to `active`.
+
+        Active keys sign and are listed as trusted, signing-only keys sign but
+        are not listed yet, and retired keys no longer sign but are still
+        listed as trusted.
+      '';
+    };
+
+    rotation = mkOption {
+      type = types.bool;
+      default = false;
+      description = ''
+        Sign with every active and signing-only key when a request doesn't
+        select a key, producing one signature per line.
+      '';
+    };
+
+    pathInfoBackend = mkOption {
+      type = types.enum [ "nix-command" "daemon" "db" "json-file" ];
+      default = "nix-command";
+      description = ''
+        Where to get the path info of store paths from: by running
+        `nix path-info`, by asking the Nix daemon over its socket, by
+        reading the Nix store database directly, or from `pathInfoJson`.
+      '';
+    };
+
+    pathInfoJson = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      description = ''
+        A file holding the output of `nix path-info --json`, used by the
+        `json-file` path info backend.
+      '';
+    };
+
+    maxPathInfoLookups = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      description = ''
+        How many path info lookups may run at once. Requests needing more are
+        turned away with 429 Too Many Requests.
+      '';
+    };
+
+    rateLimit = {
+      rate = mkOption {
+        type = types.nullOr (types.either types.ints.positive types.float);
+        default = null;
+        example = 5;
+        description = ''
+          How many requests a second each client may make, on average. Requests
+          are counted against the address they came from, even if they fail to
+          authenticate, and separately against the bearer token, certificate or
+          Unix user that made them.
+        '';
+      };
+
+      burst = mkOption {
+        type = types.nullOr types.ints.positive;
+        default = null;
+        description = ''
+          How many requests a client may make at once before the rate limit
+          applies. Defaults to a second's worth.
+        '';
+      };
+    };
+
+    tls = {
+      certificateFile = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description = ''
+          A PEM certificate chain to serve HTTPS with. Without one, plain HTTP
+          is served.
+
+          The certificate, key and client CA are read again when the service
+          is reloaded.
+        '';
+      };
+
+      keyFile = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description = ''
+          The PEM private key for `tls.certificateFile`.
+        '';
+      };
+
+      clientCaFile = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description = ''
+          PEM CA certificates to verify client certificates against. A verified
+          client certificate identifies the caller by its subject alternative
+          names or common name, which `authFile` rules can match with
+          `"certificate": "<name>"`.
+        '';
+      };
+
+      requireClientCertificate = mkOption {
+        type = types.bool;
+        default = false;
+        description = ''
+          Refuse clients that don't present a certificate signed by
+          `tls.clientCaFile`.
+        '';
+      };
+    };
+
+    authFile = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "/run/secrets/cache-signing-server-auth.json";
+      description = ''
+        A JSON file listing the bearer tokens allowed to use the server, as
+        SHA-256 hashes, with the operations and keys each may use:
+
+          [
+            {
+              "name": "hydra",
+              "token": "sha256:<base16 hash of the token>",
+              "operations": [ "sign-store-path", "publickey" ],
+              "keys": [ "cache.example.org-1" ]
+            }
+          ]
+
+        Rules may name a client certificate with `"certificate": "<name>"`,
+        or a local user or group with `"uid": <uid>` or `"gid": <gid>`,
+        instead of a token. Operations are `sign`, `sign-store-path`,
+        `publickey`, `verify`, `log` and `metrics`, and `keys` may be left out
+        to allow every key. Without an auth file, anyone who can connect may
+        use every route and key.
+      '';
+    };
+
+    policyFile = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      description = ''
+        A JSON file of rules for which store paths `/sign-store-path`,
+        `/sign-narinfo`, `/sign` and `/sign/batch` may sign. The first rule
+        applying to a path decides, and paths no rule applies to are denied
+        unless `default` is `allow`:
+
+          {
+            "default": "deny",
+            "rules": [
+              { "name": "no-debug", "action": "deny", "outputs": [ "debug" ] },
+              {
+                "name": "firefox",
+                "action": "allow",
+                "names": [ "firefox-*" ],
+                "derivers": [ "firefox-*.drv" ],
+                "maxNarSize": 1000000000,
+                "requiredReferences": [ "glibc-*" ],
+                "forbiddenReferences": [ "*-source" ]
+              }
+            ]
+          }
+
+        Rules apply to paths matching all of their `names`, `outputs` and
+        `derivers`, which match anything when left out. Paths that an allow
+        rule applies to must also be within `maxNarSize`, have a reference
+        matching every `requiredReferences` glob, and none matching a
+        `forbiddenReferences` glob. Without a policy file, any store path
+        may be signed.
+
+        Deny rules with `outputs` or `derivers` apply to paths without a
+        deriver, like anything signed without `verifyFingerprints` (a
+        narinfo's `Deriver:` isn't trusted), while allow rules with them
+        don't.
+        Malformed fingerprints can't be signed at all.
+      '';
+    };
+
+    allowMalformedFingerprints = mkOption {
+      type = types.bool;
+      default = false;
+      description = ''
+        Whether `/sign` and `/sign/batch` may sign anything, rather than only
+        well-formed fingerprints. Only enable this for legacy clients, since it
+        lets callers get signatures over arbitrary data.
+      '';
+    };
+
+    verifyFingerprints = mkOption {
+      type = types.bool;
+      default = false;
+      description = ''
+        Whether `/sign` and `/sign/batch` should only sign fingerprints, and
+        `/sign-narinfo` only narinfos, for store paths in the local store, with
+        the same NAR hash, NAR size and references. Can't be combined with `allowMalformedFingerprints`.
+      '';
+    };
+
+    auditLog = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "/var/lib/cache-signing-server/audit.jsonl";
+      description = ''
+        A file to append a JSON record of every signature to, with each record
+        holding the hash of the one before it. Check it with
+        `nixos-cache-signing-server verify-audit-log <path>`.
+      '';
+    };
+
+    transparencyLog = {
+      file = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        example = "/var/lib/cache-signing-server/transparency-log";
+        description = ''
+          A file to keep a Merkle tree transparency log of every signature in,
+          served from `/log/tree-head`, `/log/inclusion-proof` and
+          `/log/consistency-proof`.
+        '';
+      };
+
+      keyFile = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        description = ''
+          A Nix secret key file to sign tree heads with, instead of the primary
+          key. Required if `allowMalformedFingerprints` is enabled.
+        '';
+      };
+    };
+
+    trustedProxies = mkOption {
+      type = types.listOf types.str;
+      default = [ ];
+      example = [ "127.0.0.1" "::1" "10.0.0.0/8" ];
+      description = ''
+        Reverse proxies, as addresses or CIDR networks, whose
+        `clientAddrHeader` is believed about who the client is.
+      '';
+    };
+
+    clientAddrHeader = mkOption {
+      type = types.enum [ "forwarded" "x-forwarded-for" "x-real-ip" ];
+      default = "x-forwarded-for";
+      description = ''
+        The header `trustedProxies` say who they forwarded for in. No other
+        forwarding header is read, so it must be one the proxies always set
+        or overwrite.
+      '';
+    };
+
+    metricsBind = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "127.0.0.1:9090";
+      description = ''
+        An address to serve Prometheus metrics on, without authorization.
+        Without one, `/metrics` is served alongside the API.
+      '';
+    };
+
+    otlp = {
+      endpoint = mkOption {
+        type = types.nullOr types.str;
+        default = null;
+        example = "http://localhost:4317";
+        description = ''
+          An OpenTelemetry collector to export traces to over OTLP.
+          Callers' W3C `traceparent` headers are honoured.
+        '';
+      };
+
+      protocol = mkOption {
+        type = types.enum [ "grpc" "http" ];
+        default = "grpc";
+        description = ''
+          Whether to export traces over gRPC or as protobuf over HTTP.
+        '';
+      };
+
+      serviceName = mkOption {
+        type = types.str;
+        default = "nixos-cache-signing-server";
+        description = ''
+          The service name to export traces as.
+        '';
+      };
+
+      samplingRatio = mkOption {
+        type = types.numbers.between 0 1;
+        default = 1;
+        description = ''
+          The fraction of traces to export, for requests without a `traceparent`.
+        '';
+      };
+    };
+
     verbosity = mkOption {
       type = types.enum [ 0 1 2 ];
       default = 0;
@@ -84,10 +428,27 @@ in
   };
 
   config = mkIf cfg.enable {
+    assertions = [
+      {
+        assertion = cfg.transparencyLog.file != null && cfg.allowMalformedFingerprints -> cfg.transparencyLog.keyFile != null;
+        message = "services.cache-signing-server.transparencyLog.keyFile is required with allowMalformedFingerprints";
+      }
+    ];
+
+    systemd.sockets.cache-signing-server = mkIf cfg.socketActivation {
+      description = "NixOS Cache Signing Server Socket";
+      wantedBy = [ "sockets.target" ];
+      listenStreams = [
+        (if cfg.listen != null then lib.removePrefix "unix:" cfg.listen else "${cfg.host}:${toString cfg.port}")
+      ];
+      socketConfig.FileDescriptorName = "cache-signing-server";
+    };
+
     systemd.services.cache-signing-server = {
       description = "NixOS Cache Signing Server";
       documentation = [ "https://github.com/cole-h/nixos-cache-signing-server" ];
-      wantedBy = [ "multi-user.target" ];
+      wantedBy = mkIf (!cfg.socketActivation) [ "multi-user.target" ];
+      requires = mkIf cfg.socketActivation [ "cache-signing-server.socket" ];
       wants = [ "network.target" ];
       after = [ "network-online.target" ];
       path = [ config.nix.package ];
@@ -97,12 +458,43 @@ in
       serviceConfig = {
         Restart = "always";
         RestartSec = 1;
+        # Only TLS material is reloaded, and without TLS there's no SIGHUP handler to catch it
+        ExecReload = mkIf (cfg.tls.certificateFile != null) "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
+        # systemd owns the socket when it's socket activated, so it must outlive the service
+        RuntimeDirectory = mkIf (!cfg.socketActivation) "cache-signing-server";
+        PrivateNetwork = cfg.socketActivation;
+        # Somewhere to keep the audit and transparency logs
+        StateDirectory = "cache-signing-server";
       };
 
+      # exec, so signals and socket activation reach the server rather than the shell
       script = ''
-        ${cfg.package}/bin/nixos-cache-signing-server \
-          --bind ${cfg.host}:${toString cfg.port} \
+        exec ${cfg.package}/bin/nixos-cache-signing-server \
+          ${if cfg.listen != null then "--listen ${cfg.listen}" else "--bind ${cfg.host}:${toString cfg.port}"} \
           --secret-key-file ${cfg.secretKeyFile} \
+          ${lib.concatMapStringsSep " " (file: "--secret-key-file ${file}") cfg.extraSecretKeyFiles} \
+          ${lib.optionalString (cfg.primaryKey != null) "--primary-key ${cfg.primaryKey}"} \
+          ${lib.concatStringsSep " " (lib.mapAttrsToList (name: status: "--key-status ${name}=${status}") cfg.keyStatus)} \
+          ${lib.optionalString cfg.rotation "--rotation"} \
+          --path-info-backend ${cfg.pathInfoBackend} \
+          ${lib.optionalString (cfg.pathInfoJson != null) "--path-info-json ${cfg.pathInfoJson}"} \
+          ${lib.optionalString (cfg.maxPathInfoLookups != null) "--max-path-info-lookups ${toString cfg.maxPathInfoLookups}"} \
+          ${lib.optionalString (cfg.rateLimit.rate != null) "--rate-limit ${toString cfg.rateLimit.rate}"} \
+          ${lib.optionalString (cfg.rateLimit.burst != null) "--rate-limit-burst ${toString cfg.rateLimit.burst}"} \
+          ${lib.optionalString (cfg.tls.certificateFile != null) "--tls-cert ${cfg.tls.certificateFile}"} \
+          ${lib.optionalString (cfg.tls.keyFile != null) "--tls-key ${cfg.tls.keyFile}"} \
+          ${lib.optionalString (cfg.tls.clientCaFile != null) "--tls-client-ca ${cfg.tls.clientCaFile}"} \
+          ${lib.optionalString cfg.tls.requireClientCertificate "--tls-require-client-cert"} \
+          ${lib.optionalString (cfg.authFile != null) "--auth-file ${cfg.authFile}"} \
+          ${lib.optionalString (cfg.policyFile != null) "--policy-file ${cfg.policyFile}"} \
+          ${lib.optionalString cfg.allowMalformedFingerprints "--allow-malformed-fingerprints"} \
+          ${lib.optionalString cfg.verifyFingerprints "--verify-fingerprints"} \
+          ${lib.optionalString (cfg.trustedProxies != [ ]) "--trusted-proxies ${lib.concatStringsSep "," cfg.trustedProxies} --client-addr-header ${cfg.clientAddrHeader}"} \
+          ${lib.optionalString (cfg.metricsBind != null) "--metrics-bind ${cfg.metricsBind}"} \
+          ${lib.optionalString (cfg.auditLog != null) "--audit-log ${cfg.auditLog}"} \
+          ${lib.optionalString (cfg.transparencyLog.file != null) "--transparency-log ${cfg.transparencyLog.file}"} \
+          ${lib.optionalString (cfg.transparencyLog.keyFile != null) "--transparency-log-key-file ${cfg.transparencyLog.keyFile}"} \
+          ${lib.optionalString (cfg.otlp.endpoint != null) "--otlp-endpoint ${cfg.otlp.endpoint} --otlp-protocol ${cfg.otlp.protocol} --otlp-service-name ${cfg.otlp.serviceName} --otlp-sampling-ratio ${toString cfg.otlp.samplingRatio}"} \
           ${lib.concatStringsSep " " (lib.replicate cfg.verbosity "-v")} \
           --logger ${cfg.logger} \
           ${lib.optionalString (cfg.logDirectives != null) "--log-directives ${lib.concatStringsSep "," cfg.logDirectives}"} \
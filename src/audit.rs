@@ -0,0 +1,245 @@
+//! An append-only log of every signature made, one JSON record per line.
+//!
+//! Each record holds the SHA-256 of the line before it, so editing, removing or reordering
+//! records breaks the chain. Removing records from the end can only be noticed by comparing
+//! against a chain head saved elsewhere.
+
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, WrapErr};
+use sha2::{Digest, Sha256};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::auth::Caller;
+use crate::error::Result;
+use crate::nix::Fingerprint;
+
+/// The `previous` hash of the first record.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Record {
+    /// Counts up from 0
+    pub sequence: u64,
+    /// RFC 3339, in UTC
+    pub timestamp: String,
+    pub identity: String,
+    /// Where the request came from, if known
+    pub source: Option<String>,
+    pub key: String,
+    /// `None` if the signature wasn't made over a well-formed fingerprint
+    pub store_path: Option<String>,
+    /// In `sha256:<base32>` form, `None` like `store_path`
+    pub nar_hash: Option<String>,
+    /// The SHA-256 of the fingerprint, in hex
+    pub fingerprint_sha256: String,
+    /// Base64, without the key name
+    pub signature: String,
+    /// The SHA-256 of the previous line without its newline, in hex
+    pub previous: String,
+}
+
+/// Where a verified log ends.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainHead {
+    pub records: u64,
+    /// The SHA-256 of the last line, or all zeroes for an empty log
+    pub hash: String,
+}
+
+impl ChainHead {
+    fn genesis() -> Self {
+        Self {
+            records: 0,
+            hash: String::from(GENESIS_HASH),
+        }
+    }
+}
+
+pub struct AuditLog {
+    chain: Mutex<Chain>,
+}
+
+struct Chain {
+    file: tokio::fs::File,
+    len: u64,
+    head: ChainHead,
+}
+
+impl AuditLog {
+    /// Open the log for appending, after checking the records already in it and dropping any
+    /// partly written record at the end from a crash.
+    #[tracing::instrument]
+    pub async fn open(path: &Path) -> Result<Self> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)
+            .await
+            .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+
+        let mut contents = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut file, &mut contents)
+            .await
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+
+        let complete = contents
+            .iter()
+            .rposition(|&c| c == b'\n')
+            .map_or(0, |newline| newline + 1);
+        if complete != contents.len() {
+            // It was never synced, so the signatures it records were never handed out
+            tracing::warn!(
+                "dropping {} bytes of a partly written audit record",
+                contents.len() - complete
+            );
+            file.set_len(complete as u64).await?;
+        }
+
+        let head = verify_contents(path, &contents[..complete])?;
+        tracing::debug!("audit log has {} records", head.records);
+
+        Ok(Self {
+            chain: Mutex::new(Chain {
+                file,
+                len: complete as u64,
+                head,
+            }),
+        })
+    }
+
+    /// Record every signature, given each fingerprint with its signatures as `sign_with_keys`
+    /// returns them, and only return once they're on disk.
+    pub async fn record<'a>(
+        &self,
+        caller: &Caller,
+        signed: impl IntoIterator<Item = (&'a [u8], &'a str)>,
+    ) -> Result<()> {
+        let timestamp = OffsetDateTime::now_utc().format(&Rfc3339)?;
+
+        let mut chain = self.chain.lock().await;
+        let mut head = chain.head.clone();
+        let mut lines = Vec::new();
+
+        for (fingerprint, signatures) in signed {
+            let parsed = std::str::from_utf8(fingerprint)
+                .ok()
+                .and_then(|fingerprint| Fingerprint::parse(fingerprint).ok());
+            let nar_hash = match &parsed {
+                Some(parsed) => Some(parsed.nar_hash.to_nix_base32()?.to_string()),
+                None => None,
+            };
+            let fingerprint_sha256 = hex::encode(Sha256::digest(fingerprint));
+
+            for signature in signatures.lines() {
+                let (key, signature) = signature
+                    .split_once(':')
+                    .ok_or_else(|| eyre!("Signature '{signature}' has no key name"))?;
+
+                let record = Record {
+                    sequence: head.records,
+                    timestamp: timestamp.clone(),
+                    identity: caller.identity.clone(),
+                    source: caller.source.clone(),
+                    key: key.to_string(),
+                    store_path: parsed.as_ref().map(|parsed| parsed.store_path.clone()),
+                    nar_hash: nar_hash.clone(),
+                    fingerprint_sha256: fingerprint_sha256.clone(),
+                    signature: signature.to_string(),
+                    previous: head.hash,
+                };
+                let line = serde_json::to_vec(&record)?;
+
+                head = ChainHead {
+                    records: head.records + 1,
+                    hash: hex::encode(Sha256::digest(&line)),
+                };
+                lines.extend(line);
+                lines.push(b'\n');
+            }
+        }
+
+        if let Err(err) = chain.append(&lines).await {
+            // Don't leave half a record behind for the next one to chain from
+            if let Err(err) = chain.file.set_len(chain.len).await {
+                tracing::error!("failed to truncate the audit log after a failed write: {err}");
+            }
+            return Err(err);
+        }
+        chain.len += lines.len() as u64;
+        chain.head = head;
+
+        Ok(())
+    }
+}
+
+impl Chain {
+    async fn append(&mut self, lines: &[u8]) -> Result<()> {
+        self.file
+            .write_all(lines)
+            .await
+            .wrap_err("Failed to write to the audit log")?;
+        self.file
+            .sync_data()
+            .await
+            .wrap_err("Failed to sync the audit log")?;
+
+        Ok(())
+    }
+}
+
+/// Check every record in the log chains from the one before it.
+#[tracing::instrument]
+pub async fn verify(path: &Path) -> Result<ChainHead> {
+    let contents = tokio::fs::read(path)
+        .await
+        .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+
+    verify_contents(path, &contents)
+}
+
+fn verify_contents(path: &Path, contents: &[u8]) -> Result<ChainHead> {
+    let mut head = ChainHead::genesis();
+    if contents.is_empty() {
+        return Ok(head);
+    }
+    let contents = contents
+        .strip_suffix(b"\n")
+        .ok_or_else(|| eyre!("{} ends with a partial record", path.display()))?;
+
+    for (index, line) in contents.split(|&c| c == b'\n').enumerate() {
+        let line_number = index + 1;
+        let record: Record = serde_json::from_slice(line)
+            .wrap_err_with(|| format!("Line {line_number} of {} isn't a record", path.display()))?;
+
+        if record.sequence != head.records {
+            return Err(eyre!(
+                "Line {line_number} has sequence number {} rather than {}",
+                record.sequence,
+                head.records
+            )
+            .into());
+        }
+        if record.previous != head.hash {
+            return Err(eyre!(
+                "Line {line_number} follows a record with hash {} rather than {}",
+                record.previous,
+                head.hash
+            )
+            .into());
+        }
+
+        head = ChainHead {
+            records: head.records + 1,
+            hash: hex::encode(Sha256::digest(line)),
+        };
+    }
+
+    Ok(head)
+}
@@ -0,0 +1,324 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
+
+use axum::extract::{ConnectInfo, Extension, MatchedPath, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use color_eyre::eyre::WrapErr;
+
+use crate::client_addr::ClientAddr;
+use crate::error::{AppError, Result};
+use crate::keyring::KeyEntry;
+use crate::listener::{Peer, PeerAddr};
+use crate::nix::SRIHash;
+use crate::AppContext;
+
+/// Something a caller can be allowed to do, one per group of routes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde_derive::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    /// `/sign`, `/sign/batch` and `/sign-narinfo`
+    Sign,
+    /// `/sign-store-path` and `/sign-store-path/batch`
+    SignStorePath,
+    /// `/publickey`
+    Publickey,
+    /// `/verify`
+    Verify,
+    /// `/log/tree-head`, `/log/inclusion-proof` and `/log/consistency-proof`
+    Log,
+    /// `/metrics`, unless it's served on `--metrics-bind`
+    Metrics,
+}
+
+impl Operation {
+    fn from_route(route: &str) -> Option<Self> {
+        match route {
+            "/sign" | "/sign/batch" | "/sign-narinfo" => Some(Operation::Sign),
+            "/sign-store-path" | "/sign-store-path/batch" => Some(Operation::SignStorePath),
+            "/publickey" => Some(Operation::Publickey),
+            "/verify" => Some(Operation::Verify),
+            "/log/tree-head" | "/log/inclusion-proof" | "/log/consistency-proof" => {
+                Some(Operation::Log)
+            }
+            "/metrics" => Some(Operation::Metrics),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operation = match self {
+            Operation::Sign => "sign",
+            Operation::SignStorePath => "sign-store-path",
+            Operation::Publickey => "publickey",
+            Operation::Verify => "verify",
+            Operation::Log => "log",
+            Operation::Metrics => "metrics",
+        };
+        f.write_str(operation)
+    }
+}
+
+/// Who a rule in the auth file applies to.
+#[derive(Clone, Debug, serde_derive::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Principal {
+    /// The SHA-256 hash of a bearer token, as `sha256:<base16>` or an SRI hash
+    Token(String),
+    /// A name in a verified client certificate, either a subject alternative name or the common name
+    Certificate(String),
+    /// A user connecting over a Unix socket
+    Uid(u32),
+    /// A primary group connecting over a Unix socket
+    Gid(u32),
+}
+
+impl Principal {
+    /// Whether the principal is the peer on the other end of the connection.
+    fn matches(&self, peer: &Peer) -> bool {
+        match (self, &peer.addr, &peer.certificate) {
+            (Principal::Certificate(name), _, Some(certificate)) => {
+                certificate.names.contains(name)
+            }
+            (Principal::Uid(uid), PeerAddr::Unix { uid: peer_uid, .. }, _) => uid == peer_uid,
+            (Principal::Gid(gid), PeerAddr::Unix { gid: peer_gid, .. }, _) => gid == peer_gid,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde_derive::Deserialize)]
+pub struct Permissions {
+    /// A name for the caller in logs, defaults to a description of the principal
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub principal: Principal,
+    pub operations: BTreeSet<Operation>,
+    /// The keys the caller may use, or all keys if unset
+    pub keys: Option<BTreeSet<String>>,
+}
+
+/// Who made a request, and what they are allowed to do.
+#[derive(Clone, Debug)]
+pub struct Caller {
+    pub identity: String,
+    /// Where the request came from, if known
+    pub source: Option<String>,
+    /// `None` if authorization is disabled, and the caller may do anything
+    permissions: Option<Arc<Permissions>>,
+}
+
+/// The identity of callers nobody knows anything about.
+const ANONYMOUS: &str = "anonymous";
+
+impl Caller {
+    pub fn anonymous() -> Self {
+        Self {
+            identity: String::from(ANONYMOUS),
+            source: None,
+            permissions: None,
+        }
+    }
+
+    /// Who to count the caller's requests against, if they're known.
+    ///
+    /// Anyone else is only counted by where they connected from.
+    pub fn rate_limit_key(&self) -> Option<&str> {
+        (self.identity != ANONYMOUS).then_some(self.identity.as_str())
+    }
+
+    fn check_operation(&self, operation: Operation) -> Result<()> {
+        match &self.permissions {
+            Some(permissions) if !permissions.operations.contains(&operation) => {
+                Err(AppError::Forbidden(format!("{} may not {operation}", self.identity)).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the caller may use the key.
+    pub fn may_use(&self, key: &KeyEntry) -> bool {
+        match self
+            .permissions
+            .as_ref()
+            .and_then(|permissions| permissions.keys.as_ref())
+        {
+            Some(allowed) => allowed.contains(&key.name),
+            None => true,
+        }
+    }
+
+    /// Check the caller may use every key.
+    pub fn check_keys<'a>(&self, keys: impl IntoIterator<Item = &'a KeyEntry>) -> Result<()> {
+        match keys.into_iter().find(|key| !self.may_use(key)) {
+            Some(key) => Err(AppError::Forbidden(format!(
+                "{} may not use key '{}'",
+                self.identity, key.name
+            ))
+            .into()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenRule {
+    hash: ssri::Hash,
+    permissions: Arc<Permissions>,
+}
+
+/// A rule for whoever is on the other end of the connection, rather than what they send.
+#[derive(Debug)]
+struct PeerRule {
+    identity: String,
+    permissions: Arc<Permissions>,
+}
+
+#[derive(Debug, Default)]
+pub struct Authorizer {
+    tokens: Vec<TokenRule>,
+    peers: Vec<PeerRule>,
+}
+
+impl Authorizer {
+    /// Load a JSON auth file holding a list of [`Permissions`].
+    #[tracing::instrument]
+    pub async fn load(auth_file: &Path) -> Result<Self> {
+        let contents = tokio::fs::read(auth_file)
+            .await
+            .wrap_err_with(|| format!("Failed to read {}", auth_file.display()))?;
+        let rules: Vec<Permissions> = serde_json::from_slice(&contents)
+            .wrap_err_with(|| format!("Failed to parse {}", auth_file.display()))?;
+
+        Self::from_rules(rules)
+    }
+
+    pub fn from_rules(rules: Vec<Permissions>) -> Result<Self> {
+        let mut authorizer = Self::default();
+
+        for permissions in rules {
+            let identity = match &permissions.principal {
+                Principal::Token(hash) => {
+                    let SRIHash(hash) = SRIHash::from_nix_hash(hash)
+                        .map_err(|_| color_eyre::eyre::eyre!("Invalid token hash '{hash}'"))?;
+                    if hash.algorithm != ssri::Algorithm::Sha256 {
+                        return Err(color_eyre::eyre::eyre!(
+                            "Token hash '{hash}' is not a SHA-256 hash"
+                        )
+                        .into());
+                    }
+
+                    authorizer.tokens.push(TokenRule {
+                        hash,
+                        permissions: Arc::new(permissions),
+                    });
+                    continue;
+                }
+                Principal::Certificate(name) => format!("certificate {name}"),
+                Principal::Uid(uid) => format!("uid {uid}"),
+                Principal::Gid(gid) => format!("gid {gid}"),
+            };
+
+            authorizer.peers.push(PeerRule {
+                identity: permissions.name.clone().unwrap_or(identity),
+                permissions: Arc::new(permissions),
+            });
+        }
+
+        Ok(authorizer)
+    }
+
+    /// Find out who is calling from the connection, or else the request headers.
+    pub fn identify(&self, peer: Option<&Peer>, headers: &axum::http::HeaderMap) -> Result<Caller> {
+        if let Some(peer) = peer {
+            let rule = self
+                .peers
+                .iter()
+                .find(|rule| rule.permissions.principal.matches(peer));
+            if let Some(rule) = rule {
+                return Ok(Caller {
+                    identity: rule.identity.clone(),
+                    source: None,
+                    permissions: Some(rule.permissions.clone()),
+                });
+            }
+        }
+
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|authorization| authorization.to_str().ok())
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .ok_or(AppError::Unauthorized)?;
+
+        let integrity = ssri::IntegrityOpts::new()
+            .algorithm(ssri::Algorithm::Sha256)
+            .chain(token.trim())
+            .result();
+        let hash = &integrity.hashes[0];
+
+        let rule = self
+            .tokens
+            .iter()
+            .find(|rule| &rule.hash == hash)
+            .ok_or(AppError::Unauthorized)?;
+
+        Ok(Caller {
+            identity: rule
+                .permissions
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("token {}", &rule.hash.digest[..8])),
+            source: None,
+            permissions: Some(rule.permissions.clone()),
+        })
+    }
+}
+
+/// Identify the caller, check they may use the route, and make them available to handlers.
+pub async fn authorize<B>(
+    State(state): State<AppContext>,
+    matched_path: Option<MatchedPath>,
+    peer: Option<ConnectInfo<Peer>>,
+    client_addr: Option<Extension<ClientAddr>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Result<Response> {
+    let peer = peer.as_ref().map(|ConnectInfo(peer)| peer);
+    let mut caller = match (&state.authorizer, peer.and_then(Peer::identity)) {
+        (Some(authorizer), _) => authorizer.identify(peer, request.headers())?,
+        // Anyone may do anything, but it's still worth knowing who they were
+        (None, Some(identity)) => Caller {
+            identity,
+            source: None,
+            permissions: None,
+        },
+        (None, None) => Caller::anonymous(),
+    };
+    caller.source = client_addr.map(|Extension(client_addr)| client_addr.to_string());
+    tracing::Span::current().record("identity", tracing::field::display(&caller.identity));
+
+    let route = matched_path
+        .as_ref()
+        .map_or("<unmatched>", MatchedPath::as_str);
+    match Operation::from_route(route) {
+        Some(operation) => caller.check_operation(operation)?,
+        // Nobody can have been allowed a route no operation covers
+        None if state.authorizer.is_some() => {
+            return Err(AppError::Forbidden(format!(
+                "{route} isn't covered by any operation, so {} may not use it",
+                caller.identity
+            ))
+            .into());
+        }
+        None => (),
+    }
+
+    request.extensions_mut().insert(caller);
+
+    Ok(next.run(request).await)
+}
@@ -0,0 +1,62 @@
+use axum::http::header::CONTENT_TYPE;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+
+use crate::error::{AppError, Result};
+
+/// The format of a batch request body, which is also used for its response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchFormat {
+    /// A JSON array of strings, answered with a JSON array of strings
+    Json,
+    /// One item per line, answered with one line per item
+    Lines,
+}
+
+impl BatchFormat {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let is_json = headers
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("application/json"));
+
+        if is_json {
+            BatchFormat::Json
+        } else {
+            BatchFormat::Lines
+        }
+    }
+
+    #[tracing::instrument(skip(body))]
+    pub fn parse(self, body: &[u8]) -> Result<Vec<String>> {
+        let items = match self {
+            BatchFormat::Json => serde_json::from_slice(body)
+                .map_err(|e| AppError::MalformedRequestBody(e.to_string()))?,
+            BatchFormat::Lines => std::str::from_utf8(body)
+                .map_err(|e| AppError::MalformedRequestBody(e.to_string()))?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect(),
+        };
+
+        Ok(items)
+    }
+
+    /// Build the response for a list of signing results, in request order.
+    ///
+    /// Each result holds one signature per line, which are joined with spaces
+    /// when responding with lines so every item stays on a single line.
+    pub fn respond(self, signatures: Vec<String>) -> Response {
+        match self {
+            BatchFormat::Json => axum::Json(signatures).into_response(),
+            BatchFormat::Lines => signatures
+                .iter()
+                .map(|signature| signature.lines().collect::<Vec<_>>().join(" "))
+                .collect::<Vec<_>>()
+                .join("\n")
+                .into_response(),
+        }
+    }
+}
@@ -2,6 +2,11 @@ use std::error::Error;
 use std::io::IsTerminal;
 
 use color_eyre::eyre::WrapErr;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, Tracer};
+use opentelemetry_sdk::Resource;
 use tracing::Subscriber;
 use tracing_subscriber::filter::Directive;
 use tracing_subscriber::layer::{Layer, SubscriberExt};
@@ -10,6 +15,7 @@ use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
 
 use super::logger::Logger;
+use super::otlp::OtlpProtocol;
 
 #[derive(clap::Args, Debug, Default)]
 pub struct Instrumentation {
@@ -32,6 +38,25 @@ pub struct Instrumentation {
     /// See https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html#directives
     #[clap(long, global = true, value_delimiter = ',', num_args = 0..)]
     pub log_directives: Vec<Directive>,
+    /// Export traces to this OTLP collector, like `http://localhost:4317`
+    #[clap(long, global = true)]
+    pub otlp_endpoint: Option<String>,
+    /// How to talk to the OTLP collector
+    #[clap(long, default_value_t = Default::default(), global = true)]
+    pub otlp_protocol: OtlpProtocol,
+    /// The service name to export traces as
+    #[clap(long, default_value = env!("CARGO_PKG_NAME"), global = true)]
+    pub otlp_service_name: String,
+    /// The fraction of traces to export, unless a `traceparent` from the caller says otherwise
+    #[clap(long, default_value_t = 1.0, value_parser = parse_ratio, global = true)]
+    pub otlp_sampling_ratio: f64,
+}
+
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!("expected a number from 0 to 1, got `{s}`")),
+    }
 }
 
 impl Instrumentation {
@@ -47,9 +72,14 @@ impl Instrumentation {
     pub fn setup(&self) -> color_eyre::Result<()> {
         let filter_layer = self.filter_layer()?;
 
+        let otlp_layer = self
+            .otlp_tracer()?
+            .map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+
         let registry = tracing_subscriber::registry()
             .with(filter_layer)
-            .with(tracing_error::ErrorLayer::default());
+            .with(tracing_error::ErrorLayer::default())
+            .with(otlp_layer);
 
         // `try_init` called inside `match` since `with` changes the type
         match self.logger {
@@ -62,6 +92,45 @@ impl Instrumentation {
         Ok(())
     }
 
+    /// Start exporting traces to `--otlp-endpoint`, if it was given.
+    pub fn otlp_tracer(&self) -> color_eyre::Result<Option<Tracer>> {
+        let Some(endpoint) = &self.otlp_endpoint else {
+            return Ok(None);
+        };
+
+        let exporter: SpanExporterBuilder = match self.otlp_protocol {
+            OtlpProtocol::Grpc => opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint)
+                .into(),
+            // Only the gRPC exporter adds the path itself
+            OtlpProtocol::Http => opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+                .into(),
+        };
+        let config = opentelemetry_sdk::trace::config()
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                self.otlp_sampling_ratio,
+            ))))
+            .with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                self.otlp_service_name.clone(),
+            )]));
+
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(exporter)
+            .with_trace_config(config)
+            .install_batch(opentelemetry_sdk::runtime::Tokio)
+            .wrap_err_with(|| format!("Failed to set up exporting traces to {endpoint}"))?;
+
+        // Lets `trace_layer` continue the caller's trace from their `traceparent`
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+        Ok(Some(tracer))
+    }
+
     pub fn filter_layer(&self) -> color_eyre::Result<EnvFilter> {
         let mut filter_layer = match EnvFilter::try_from_default_env() {
             Ok(layer) => layer,
@@ -75,7 +144,7 @@ impl Instrumentation {
                 }
                 // If the `--log-directives` is specified, don't set a default
                 if self.log_directives.is_empty() {
-                    EnvFilter::try_new(&format!(
+                    EnvFilter::try_new(format!(
                         "{}={}",
                         env!("CARGO_PKG_NAME").replace('-', "_"),
                         self.log_level()
@@ -0,0 +1,33 @@
+use std::path::PathBuf;
+
+use crate::keyring::KeyStatus;
+
+#[derive(clap::Args, Debug, Default)]
+pub struct KeyringArgs {
+    /// A Nix secret key file to add to the keyring, may be given multiple times
+    #[clap(long, required = true)]
+    pub secret_key_file: Vec<PathBuf>,
+
+    /// The name of the key used when a request doesn't select one, defaults to the first key
+    #[clap(long)]
+    pub primary_key: Option<String>,
+
+    /// The rotation status of a key, as `<name>=<active|signing-only|retired>`
+    ///
+    /// Keys default to `active`.
+    #[clap(long, value_parser = parse_key_status)]
+    pub key_status: Vec<(String, KeyStatus)>,
+
+    /// Sign with every key that is able to sign when a request doesn't select a key
+    #[clap(long)]
+    pub rotation: bool,
+}
+
+fn parse_key_status(s: &str) -> Result<(String, KeyStatus), String> {
+    let (name, status) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected `<name>=<status>`, got `{s}`"))?;
+    let status = <KeyStatus as clap::ValueEnum>::from_str(status, false)?;
+
+    Ok((name.to_string(), status))
+}
@@ -1,19 +1,151 @@
-mod instrumentation;
+pub mod instrumentation;
+pub mod keyring;
 mod logger;
+pub mod otlp;
+pub mod path_info;
+pub mod tls;
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroU32;
 use std::path::PathBuf;
 
+use crate::listener::ListenAddr;
+
 #[derive(Parser)]
-#[clap(version)]
+#[clap(version, subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     #[clap(long, default_value_t = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 8080))]
     pub bind: SocketAddr,
 
+    /// Where to listen instead of `--bind`, as `<ip>:<port>` or `unix:<path>`
+    ///
+    /// Callers on a Unix socket are identified by their uid and gid. Both this and `--bind` are
+    /// ignored when systemd passes sockets with `LISTEN_FDS`.
+    #[clap(long, conflicts_with = "bind")]
+    pub listen: Option<ListenAddr>,
+
+    /// Serve `/metrics` on this address, without authorization, instead of alongside the API
     #[clap(long)]
-    pub secret_key_file: PathBuf,
+    pub metrics_bind: Option<SocketAddr>,
+
+    /// Reverse proxies, as addresses or CIDR networks, whose `--client-addr-header` says who the
+    /// client is
+    ///
+    /// The header is ignored from anyone else.
+    #[clap(long, value_delimiter = ',', value_parser = crate::client_addr::parse_trusted_proxy)]
+    pub trusted_proxies: Vec<ipnet::IpNet>,
+
+    /// The header `--trusted-proxies` say who they forwarded for in
+    ///
+    /// No other forwarding header is read, so it must be one the proxies always set or overwrite.
+    #[clap(long, default_value_t = Default::default())]
+    pub client_addr_header: crate::client_addr::ClientAddrHeader,
+
+    /// How many requests a second each client may make, on average
+    ///
+    /// Requests are counted against the address they came from, even if they fail to authenticate,
+    /// and separately against the bearer token, certificate or Unix user that made them.
+    #[clap(long, value_parser = parse_rate)]
+    pub rate_limit: Option<f64>,
+
+    /// How many requests a client may make at once before `--rate-limit` applies, defaults to a
+    /// second's worth
+    #[clap(long, requires = "rate_limit")]
+    pub rate_limit_burst: Option<NonZeroU32>,
+
+    #[clap(flatten)]
+    pub keyring: keyring::KeyringArgs,
+
+    #[clap(flatten)]
+    pub tls: tls::TlsArgs,
+
+    #[clap(flatten)]
+    pub path_info: path_info::PathInfoArgs,
+
+    /// A JSON file listing bearer token hashes and what each token may do
+    ///
+    /// Without one, anyone who can connect may use every route and key.
+    #[clap(long)]
+    pub auth_file: Option<PathBuf>,
+
+    /// A JSON file of rules for which store paths may be signed
+    ///
+    /// Without one, any store path may be signed. Deny rules with `outputs` or `derivers` apply to
+    /// paths without a deriver, like anything signed without `--verify-fingerprints` (a narinfo's
+    /// `Deriver:` isn't trusted), while allow rules with them don't. Malformed fingerprints can't be
+    /// signed at all.
+    #[clap(long)]
+    pub policy_file: Option<PathBuf>,
+
+    /// Let `/sign` and `/sign/batch` sign anything, not just well-formed fingerprints
+    ///
+    /// Only for legacy clients that sign something else, since it lets callers get a signature
+    /// over arbitrary data.
+    #[clap(long)]
+    pub allow_malformed_fingerprints: bool,
+
+    /// Only sign fingerprints on `/sign` and `/sign/batch`, and narinfos on `/sign-narinfo`, for
+    /// store paths we have, with the same NAR hash, NAR size and references
+    ///
+    /// Uses the same path info backend as `/sign-store-path`.
+    #[clap(long, conflicts_with = "allow_malformed_fingerprints")]
+    pub verify_fingerprints: bool,
+
+    /// A file to append a hash-chained JSON record of every signature to
+    #[clap(long)]
+    pub audit_log: Option<PathBuf>,
+
+    /// A file to keep the leaf hashes of a Merkle tree transparency log of every signature in
+    ///
+    /// Enables `/log/tree-head`, `/log/inclusion-proof` and `/log/consistency-proof`.
+    #[clap(long)]
+    pub transparency_log: Option<PathBuf>,
+
+    /// A Nix secret key file to sign tree heads with, instead of the primary key
+    ///
+    /// Required with `--allow-malformed-fingerprints`, or callers could get a tree head signed.
+    #[clap(long, requires = "transparency_log")]
+    pub transparency_log_key_file: Option<PathBuf>,
 
     #[clap(flatten)]
     pub instrumentation: instrumentation::Instrumentation,
 }
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// Check the hash chain of an `--audit-log`, printing its length and last hash
+    VerifyAuditLog { path: PathBuf },
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("expected a positive number, got `{s}`")),
+    }
+}
+
+impl Cli {
+    /// Check the rules between arguments that clap can't express.
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        if self.transparency_log.is_some()
+            && self.allow_malformed_fingerprints
+            && self.transparency_log_key_file.is_none()
+        {
+            return Err(Self::command().error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--transparency-log-key-file is required with --transparency-log and \
+                --allow-malformed-fingerprints, or callers could get a tree head signed",
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn listen_addr(&self) -> ListenAddr {
+        self.listen.clone().unwrap_or(ListenAddr::Tcp(self.bind))
+    }
+}
@@ -0,0 +1,18 @@
+#[derive(Clone, Default, Debug, clap::ValueEnum)]
+pub enum OtlpProtocol {
+    /// OTLP over gRPC, usually on port 4317
+    #[default]
+    Grpc,
+    /// OTLP as protobuf over HTTP, usually on port 4318
+    Http,
+}
+
+impl std::fmt::Display for OtlpProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let protocol = match self {
+            OtlpProtocol::Grpc => "grpc",
+            OtlpProtocol::Http => "http",
+        };
+        write!(f, "{}", protocol)
+    }
+}
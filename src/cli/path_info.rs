@@ -0,0 +1,85 @@
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+use crate::error::Result;
+use crate::metrics::MeasuredPathInfoSource;
+use crate::nix::daemon::DEFAULT_SOCKET;
+use crate::nix::db::DEFAULT_DB;
+use crate::nix::{JsonFile, NixCommand, NixDaemon, NixDb, PathInfoSource};
+use crate::rate_limit::LimitedPathInfoSource;
+
+#[derive(clap::Args, Debug)]
+pub struct PathInfoArgs {
+    /// Where to get the path info of store paths from
+    #[clap(long, default_value_t = Default::default())]
+    pub path_info_backend: PathInfoBackend,
+
+    /// The Nix daemon socket used by the `daemon` path info backend
+    #[clap(long, default_value = DEFAULT_SOCKET)]
+    pub nix_daemon_socket: PathBuf,
+
+    /// The Nix store database read by the `db` path info backend
+    #[clap(long, default_value = DEFAULT_DB)]
+    pub nix_db: PathBuf,
+
+    /// A file holding the output of `nix path-info --json`, read by the `json-file` path info backend
+    #[clap(long, required_if_eq("path_info_backend", "json-file"))]
+    pub path_info_json: Option<PathBuf>,
+
+    /// How many path info lookups may run at once, turning away any more with 429 Too Many
+    /// Requests
+    #[clap(long)]
+    pub max_path_info_lookups: Option<NonZeroUsize>,
+}
+
+impl PathInfoArgs {
+    pub fn source(&self) -> Result<Box<dyn PathInfoSource>> {
+        let source: Box<dyn PathInfoSource> = match self.path_info_backend {
+            PathInfoBackend::NixCommand => Box::new(NixCommand),
+            PathInfoBackend::Daemon => Box::new(NixDaemon::new(self.nix_daemon_socket.clone())),
+            PathInfoBackend::Db => Box::new(NixDb::new(self.nix_db.clone())),
+            PathInfoBackend::JsonFile => {
+                let path_info_json = self.path_info_json.as_deref().ok_or_else(|| {
+                    color_eyre::eyre::eyre!("The json-file backend needs --path-info-json")
+                })?;
+                Box::new(JsonFile::load(path_info_json)?)
+            }
+        };
+
+        let source = Box::new(MeasuredPathInfoSource::new(
+            self.path_info_backend.to_string(),
+            source,
+        ));
+
+        Ok(match self.max_path_info_lookups {
+            // Outside the measurement, so turned away lookups aren't counted as failures
+            Some(max_lookups) => Box::new(LimitedPathInfoSource::new(max_lookups, source)),
+            None => source,
+        })
+    }
+}
+
+#[derive(Clone, Default, Debug, clap::ValueEnum)]
+pub enum PathInfoBackend {
+    /// Run `nix path-info`
+    #[default]
+    NixCommand,
+    /// Ask the Nix daemon over its socket
+    Daemon,
+    /// Read the Nix store database directly, without needing `nix` or the daemon
+    Db,
+    /// Read a file of path info, without needing a Nix store at all
+    JsonFile,
+}
+
+impl std::fmt::Display for PathInfoBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let backend = match self {
+            PathInfoBackend::NixCommand => "nix-command",
+            PathInfoBackend::Daemon => "daemon",
+            PathInfoBackend::Db => "db",
+            PathInfoBackend::JsonFile => "json-file",
+        };
+        write!(f, "{}", backend)
+    }
+}
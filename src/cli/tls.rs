@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::error::Result;
+use crate::listener::tls::TlsAcceptor;
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct TlsArgs {
+    /// Serve HTTPS with this PEM certificate chain, instead of plain HTTP
+    ///
+    /// The certificate, key and client CA are read again on SIGHUP.
+    #[clap(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// The PEM private key for `--tls-cert`
+    #[clap(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Verify client certificates against the PEM CA certificates in this file
+    ///
+    /// The subject alternative names and common name of a verified client
+    /// certificate identify the caller.
+    #[clap(long, requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Refuse clients that don't present a certificate signed by `--tls-client-ca`
+    #[clap(long, requires = "tls_client_ca")]
+    pub tls_require_client_cert: bool,
+}
+
+impl TlsArgs {
+    /// The TLS acceptor to serve with, or `None` to serve plain HTTP.
+    pub fn acceptor(&self) -> Result<Option<Arc<TlsAcceptor>>> {
+        if self.tls_cert.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(Arc::new(TlsAcceptor::load(self.clone())?)))
+    }
+}
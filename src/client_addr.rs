@@ -0,0 +1,173 @@
+//! Who a request really came from, looking through reverse proxies we trust.
+//!
+//! Proxies say who they forwarded for with `Forwarded`, `X-Forwarded-For` or `X-Real-IP`, which
+//! anyone else could just as well send, so they're only believed from `--trusted-proxies`, and
+//! only the one `--client-addr-header` the proxies set is read.
+
+use std::net::{IpAddr, SocketAddr};
+
+use axum::extract::{ConnectInfo, State};
+use axum::http::header::FORWARDED;
+use axum::http::{HeaderMap, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use ipnet::IpNet;
+
+use crate::listener::{Peer, PeerAddr};
+use crate::AppContext;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_REAL_IP: &str = "x-real-ip";
+
+/// Where a request came from, after looking through trusted proxies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ClientAddr {
+    Ip(IpAddr),
+    /// A process connected over a Unix socket, as this user
+    Uid(u32),
+}
+
+impl std::fmt::Display for ClientAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientAddr::Ip(ip) => write!(f, "{ip}"),
+            ClientAddr::Uid(uid) => write!(f, "uid {uid}"),
+        }
+    }
+}
+
+/// The header trusted proxies say who they forwarded for in.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ClientAddrHeader {
+    /// RFC 7239 `Forwarded`
+    Forwarded,
+    #[default]
+    XForwardedFor,
+    XRealIp,
+}
+
+impl std::fmt::Display for ClientAddrHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let header = match self {
+            ClientAddrHeader::Forwarded => "forwarded",
+            ClientAddrHeader::XForwardedFor => "x-forwarded-for",
+            ClientAddrHeader::XRealIp => "x-real-ip",
+        };
+        write!(f, "{}", header)
+    }
+}
+
+/// Parse a `--trusted-proxies` entry, where a lone address is a network of just that address.
+pub fn parse_trusted_proxy(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map(|net| net.trunc())
+        .map_err(|_| format!("`{s}` isn't an address or CIDR network"))
+}
+
+#[derive(Debug, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+    /// The only forwarding header read, so a client can't send one the proxies don't overwrite
+    header: ClientAddrHeader,
+}
+
+impl TrustedProxies {
+    pub fn new(networks: Vec<IpNet>, header: ClientAddrHeader) -> Self {
+        Self { networks, header }
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(&ip))
+    }
+
+    /// Work out the client address from the peer, and the forwarding headers if the peer is a
+    /// trusted proxy.
+    ///
+    /// Each hop's claim about the one before it is only believed if that hop is trusted, so the
+    /// client is the first untrusted hop from the right, or the last one that can be read.
+    pub fn resolve(&self, peer: &Peer, headers: &HeaderMap) -> ClientAddr {
+        let mut client = match peer.addr {
+            PeerAddr::Tcp(addr) => addr.ip().to_canonical(),
+            PeerAddr::Unix { uid, .. } => return ClientAddr::Uid(uid),
+        };
+        if !self.trusts(client) {
+            return ClientAddr::Ip(client);
+        }
+
+        for hop in forwarded_hops(headers, self.header).into_iter().rev() {
+            let Some(ip) = parse_node(hop) else {
+                break;
+            };
+            client = ip.to_canonical();
+            if !self.trusts(client) {
+                break;
+            }
+        }
+
+        ClientAddr::Ip(client)
+    }
+}
+
+/// The addresses proxies say they forwarded for in `header`, from the client onwards.
+fn forwarded_hops(headers: &HeaderMap, header: ClientAddrHeader) -> Vec<&str> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+    };
+
+    match header {
+        // RFC 7239, like `for=192.0.2.60;proto=http, for="[2001:db8::1]:4711"`
+        ClientAddrHeader::Forwarded => values(FORWARDED.as_str())
+            .flat_map(|value| value.split(','))
+            .map(|element| {
+                element
+                    .split(';')
+                    .find_map(|pair| {
+                        let (name, value) = pair.split_once('=')?;
+                        name.trim()
+                            .eq_ignore_ascii_case("for")
+                            .then_some(value.trim())
+                    })
+                    // An element without `for` is a hop we can't see past
+                    .unwrap_or_default()
+            })
+            .collect(),
+        ClientAddrHeader::XForwardedFor => values(X_FORWARDED_FOR)
+            .flat_map(|value| value.split(','))
+            .collect(),
+        ClientAddrHeader::XRealIp => values(X_REAL_IP).collect(),
+    }
+}
+
+/// Read an address with an optional port, which may be quoted and, for IPv6, in brackets.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .or_else(|| {
+            node.strip_prefix('[')?
+                .strip_suffix(']')?
+                .parse::<IpAddr>()
+                .ok()
+        })
+}
+
+/// Resolve the client address and add it to the request, before it's logged.
+pub async fn resolve<B>(
+    State(state): State<AppContext>,
+    peer: Option<ConnectInfo<Peer>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if let Some(ConnectInfo(peer)) = peer {
+        let client_addr = state.trusted_proxies.resolve(&peer, request.headers());
+        request.extensions_mut().insert(client_addr);
+    }
+
+    next.run(request).await
+}
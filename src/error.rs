@@ -1,8 +1,11 @@
-use std::path::PathBuf;
-
-use axum::http::StatusCode;
+use axum::http::header::{ACCEPT, CONTENT_LENGTH, RETRY_AFTER, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
 
+use crate::keyring::KeyStatus;
+use crate::trace_layer::RequestId;
+
 pub type Result<T, E = Report> = color_eyre::eyre::Result<T, E>;
 
 pub struct Report(color_eyre::Report);
@@ -22,6 +25,12 @@ where
     }
 }
 
+impl Report {
+    pub fn app_error(&self) -> Option<&AppError> {
+        self.0.downcast_ref::<AppError>()
+    }
+}
+
 impl IntoResponse for Report {
     fn into_response(self) -> axum::response::Response {
         let err = self.0;
@@ -29,14 +38,109 @@ impl IntoResponse for Report {
 
         tracing::error!("{err_string}");
 
-        if let Some(err) = err.downcast_ref::<AppError>() {
+        let app_error = err.downcast_ref::<AppError>();
+        crate::metrics::metrics().record_error(app_error.map_or("internal", AppError::code));
+        if let Some(err) = app_error {
             return err.response();
         }
 
-        (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong").into_response()
+        ErrorBody::new("internal", String::from(INTERNAL_ERROR_MESSAGE))
+            .response(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+/// What's said about errors that are our problem rather than the caller's.
+const INTERNAL_ERROR_MESSAGE: &str = "Something went wrong";
+
+/// An error, as it's told to callers that ask for JSON.
+///
+/// Error responses carry it as an extension, for [`negotiate`] to render.
+#[derive(Clone, Debug, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorBody {
+    /// One of the [`AppError::code`]s, or `internal`
+    pub code: String,
+    pub message: String,
+    /// The `request_id` of the request's span, also sent as `X-Request-Id`
+    pub request_id: Option<String>,
+}
+
+impl ErrorBody {
+    fn new(code: &str, message: String) -> Self {
+        Self {
+            code: code.to_string(),
+            message,
+            request_id: None,
+        }
+    }
+
+    /// A plain text response, which [`negotiate`] may turn into JSON.
+    fn response(self, status: StatusCode) -> Response {
+        let mut response = (status, self.message.clone()).into_response();
+        response.extensions_mut().insert(self);
+        response
     }
 }
 
+/// Render error responses as JSON for callers whose `Accept` header prefers it to plain text.
+pub async fn negotiate<B>(request: Request<B>, next: Next<B>) -> Response {
+    let json = prefers_json(request.headers());
+    let request_id = request.extensions().get::<RequestId>().cloned();
+
+    let response = next.run(request).await;
+    let Some(body) = response.extensions().get::<ErrorBody>().cloned() else {
+        return response;
+    };
+    if !json {
+        return response;
+    }
+
+    let body = ErrorBody {
+        request_id: request_id.map(|RequestId(id)| id),
+        ..body
+    };
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    let json = axum::Json(body).into_response();
+    parts.headers.extend(json.headers().clone());
+
+    Response::from_parts(parts, json.into_body())
+}
+
+/// Whether the `Accept` header ranks JSON above plain text, which is the default.
+fn prefers_json(headers: &HeaderMap) -> bool {
+    // The quality of the most specific range matching the media type, as in RFC 9110
+    let quality = |media_type: &str, wildcard: &str| {
+        headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|range| {
+                let mut params = range.split(';');
+                let range = params.next()?.trim();
+                let specificity = if range.eq_ignore_ascii_case(media_type) {
+                    2
+                } else if range.eq_ignore_ascii_case(wildcard) {
+                    1
+                } else if range == "*/*" {
+                    0
+                } else {
+                    return None;
+                };
+                let quality = match params.find_map(|param| param.trim().strip_prefix("q=")) {
+                    Some(quality) => quality.parse::<f32>().ok()?,
+                    None => 1.0,
+                };
+                Some((specificity, quality))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0.0, |(_, quality)| quality)
+    };
+
+    quality("application/json", "application/*") > quality("text/plain", "text/*")
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum AppError {
     #[error("The Nix secret key file contained an invalid Nix secret key \
@@ -44,19 +148,143 @@ pub enum AppError {
     )]
     MalformedSecretKey,
 
-    #[error("Store path '{0}' was missing")]
-    MissingStorePath(PathBuf),
+    #[error("Store path '{0}' is not valid in the Nix store")]
+    StorePathNotValid(String),
+
+    #[error("Nix is unavailable: {0}")]
+    NixUnavailable(String),
+
+    #[error("`nix path-info` failed with {0}")]
+    NixCommandFailed(String),
+
+    #[error("`nix path-info` printed invalid JSON: {0}")]
+    InvalidNixOutput(String),
+
+    #[error("`nix path-info` printed no path info for {0}")]
+    NoPathInfo(String),
+
+    #[error("Nix does not support the {0} hash algorithm")]
+    UnsupportedHashAlgorithm(String),
+
+    #[error("No key named '{0}' is loaded")]
+    UnknownKey(String),
+
+    #[error("Key '{0}' is {1} and cannot sign")]
+    KeyCannotSign(String, KeyStatus),
+
+    #[error("The request body was malformed: {0}")]
+    MalformedRequestBody(String),
+
+    #[error("The narinfo was malformed: {0}")]
+    MalformedNarinfo(String),
+
+    #[error("The fingerprint was malformed: {0}")]
+    MalformedFingerprint(String),
+
+    #[error("The fingerprint for '{store_path}' doesn't match the local store: {reason}")]
+    FingerprintMismatch { store_path: String, reason: String },
+
+    #[error("No transparency log is kept")]
+    TransparencyLogDisabled,
+
+    #[error("The transparency log has no leaf with hash '{0}'")]
+    LogEntryNotFound(String),
+
+    #[error("Invalid transparency log query: {0}")]
+    InvalidLogQuery(String),
+
+    #[error("A valid bearer token is required")]
+    Unauthorized,
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Policy rule '{rule}' does not allow signing '{store_path}': {reason}")]
+    PolicyDenied {
+        rule: String,
+        store_path: String,
+        reason: String,
+    },
+
+    #[error("Too many requests, {reason}; retry after {retry_after} seconds")]
+    TooManyRequests { reason: String, retry_after: u64 },
 }
 
 impl AppError {
+    /// A name for the error that won't change, unlike its message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::MalformedSecretKey => "malformed_secret_key",
+            AppError::StorePathNotValid(_) => "store_path_not_valid",
+            AppError::NixUnavailable(_) => "nix_unavailable",
+            AppError::NixCommandFailed(_) => "nix_command_failed",
+            AppError::InvalidNixOutput(_) => "invalid_nix_output",
+            AppError::NoPathInfo(_) => "no_path_info",
+            AppError::UnsupportedHashAlgorithm(_) => "unsupported_hash_algorithm",
+            AppError::UnknownKey(_) => "unknown_key",
+            AppError::KeyCannotSign(..) => "key_cannot_sign",
+            AppError::MalformedRequestBody(_) => "malformed_request_body",
+            AppError::MalformedNarinfo(_) => "malformed_narinfo",
+            AppError::MalformedFingerprint(_) => "malformed_fingerprint",
+            AppError::FingerprintMismatch { .. } => "fingerprint_mismatch",
+            AppError::TransparencyLogDisabled => "transparency_log_disabled",
+            AppError::LogEntryNotFound(_) => "log_entry_not_found",
+            AppError::InvalidLogQuery(_) => "invalid_log_query",
+            AppError::Unauthorized => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::PolicyDenied { .. } => "policy_denied",
+            AppError::TooManyRequests { .. } => "too_many_requests",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            AppError::MalformedSecretKey => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::UnknownKey(_)
+            | AppError::StorePathNotValid(_)
+            | AppError::TransparencyLogDisabled
+            | AppError::LogEntryNotFound(_) => StatusCode::NOT_FOUND,
+            AppError::KeyCannotSign(..) | AppError::FingerprintMismatch { .. } => {
+                StatusCode::CONFLICT
+            }
+            AppError::MalformedRequestBody(_)
+            | AppError::MalformedNarinfo(_)
+            | AppError::MalformedFingerprint(_)
+            | AppError::InvalidLogQuery(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) | AppError::PolicyDenied { .. } => StatusCode::FORBIDDEN,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::NixUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            // Nix answered, but not with anything we can use
+            AppError::NixCommandFailed(_)
+            | AppError::InvalidNixOutput(_)
+            | AppError::NoPathInfo(_) => StatusCode::BAD_GATEWAY,
+            AppError::UnsupportedHashAlgorithm(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
     fn response(&self) -> Response {
+        let message = match self {
+            // The message is about our key file, which is none of the caller's business
+            AppError::MalformedSecretKey => String::from(INTERNAL_ERROR_MESSAGE),
+            _ => format!("{self}"),
+        };
+        let mut response = ErrorBody::new(self.code(), message).response(self.status());
+
         match self {
-            AppError::MalformedSecretKey => {
-                (StatusCode::INTERNAL_SERVER_ERROR, "Something went wront").into_response()
+            AppError::Unauthorized => {
+                response
+                    .headers_mut()
+                    .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
             }
-            AppError::MissingStorePath(_) => {
-                (StatusCode::BAD_REQUEST, format!("{self}")).into_response()
+            AppError::TooManyRequests { retry_after, .. } => {
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(*retry_after));
             }
+            _ => (),
         }
+
+        response
     }
 }
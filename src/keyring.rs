@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use color_eyre::eyre::WrapErr;
+use dryoc::constants::CRYPTO_SIGN_ED25519_PUBLICKEYBYTES;
+
+use crate::auth::Caller;
+use crate::cli::keyring::KeyringArgs;
+use crate::error::{AppError, Result};
+
+#[derive(Debug, serde_derive::Deserialize)]
+pub struct KeySelection {
+    /// The name of the key to use, defaults to the primary key
+    pub key: Option<String>,
+}
+
+/// Where a key is in its rotation lifecycle.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum KeyStatus {
+    /// Signs requests and is advertised as trusted
+    #[default]
+    Active,
+    /// Signs requests but is not advertised as trusted yet, e.g. a new key
+    /// that should sign everything before clients are told to trust it
+    SigningOnly,
+    /// No longer signs requests but is still advertised as trusted, so paths
+    /// it has already signed stay valid
+    Retired,
+}
+
+impl KeyStatus {
+    pub fn can_sign(self) -> bool {
+        matches!(self, KeyStatus::Active | KeyStatus::SigningOnly)
+    }
+
+    pub fn is_trusted(self) -> bool {
+        matches!(self, KeyStatus::Active | KeyStatus::Retired)
+    }
+}
+
+impl std::fmt::Display for KeyStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            KeyStatus::Active => "active",
+            KeyStatus::SigningOnly => "signing-only",
+            KeyStatus::Retired => "retired",
+        };
+        f.write_str(status)
+    }
+}
+
+#[derive(Debug)]
+pub struct KeyEntry {
+    pub name: String,
+    pub secret_key_path: PathBuf,
+    pub public_key: String,
+    pub status: KeyStatus,
+}
+
+impl KeyEntry {
+    #[tracing::instrument(skip_all, fields(key = %self.name))]
+    pub async fn secret_contents(&self) -> Result<String> {
+        read_secret_key_file(&self.secret_key_path).await
+    }
+
+    pub fn public_key_bytes(&self) -> Result<[u8; CRYPTO_SIGN_ED25519_PUBLICKEYBYTES]> {
+        let (_, public_key_base64) = self.public_key.split_once(':').ok_or_else(|| {
+            color_eyre::eyre::eyre!("Public key '{}' has no name", self.public_key)
+        })?;
+        let public_key_bytes = STANDARD.decode(public_key_base64)?;
+
+        public_key_bytes.try_into().map_err(|_| {
+            color_eyre::eyre::eyre!("Public key '{}' has the wrong length", self.public_key).into()
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Keyring {
+    keys: BTreeMap<String, KeyEntry>,
+    primary: String,
+    rotation: bool,
+}
+
+impl Keyring {
+    pub async fn load(args: &KeyringArgs) -> Result<Self> {
+        let mut keys = BTreeMap::new();
+        let mut first = None;
+
+        for secret_key_path in &args.secret_key_file {
+            let contents = read_secret_key_file(secret_key_path).await?;
+            let (name, _) = crate::secret_key_from_contents(&contents)?;
+            let public_key = crate::secret_key_to_public_key(&contents)?;
+
+            if let Some(existing) = keys
+                .get(&name)
+                .map(|entry: &KeyEntry| &entry.secret_key_path)
+            {
+                return Err(color_eyre::eyre::eyre!(
+                    "Key '{name}' from {} was already loaded from {}",
+                    secret_key_path.display(),
+                    existing.display()
+                )
+                .into());
+            }
+
+            tracing::debug!(key = %name, "loaded secret key from {}", secret_key_path.display());
+            first.get_or_insert_with(|| name.clone());
+            keys.insert(
+                name.clone(),
+                KeyEntry {
+                    name,
+                    secret_key_path: secret_key_path.to_path_buf(),
+                    public_key,
+                    status: KeyStatus::default(),
+                },
+            );
+        }
+
+        for (name, status) in &args.key_status {
+            let entry = keys.get_mut(name).ok_or_else(|| {
+                color_eyre::eyre::eyre!("Cannot set the status of unknown key '{name}'")
+            })?;
+            entry.status = *status;
+        }
+
+        let primary = match args.primary_key.as_deref() {
+            Some(primary) if keys.contains_key(primary) => primary.to_string(),
+            Some(primary) => {
+                return Err(color_eyre::eyre::eyre!(
+                    "Primary key '{primary}' is not one of the loaded keys"
+                )
+                .into())
+            }
+            None => first.ok_or_else(|| color_eyre::eyre::eyre!("No secret keys were provided"))?,
+        };
+
+        if !keys[&primary].status.can_sign() {
+            return Err(color_eyre::eyre::eyre!(
+                "Primary key '{primary}' is {} and cannot sign",
+                keys[&primary].status
+            )
+            .into());
+        }
+
+        Ok(Self {
+            keys,
+            primary,
+            rotation: args.rotation,
+        })
+    }
+
+    pub fn primary(&self) -> &KeyEntry {
+        &self.keys[&self.primary]
+    }
+
+    pub fn get(&self, name: &str) -> Option<&KeyEntry> {
+        self.keys.get(name)
+    }
+
+    /// Select the named key, or the primary key if no name was given.
+    pub fn select(&self, name: Option<&str>) -> Result<&KeyEntry> {
+        match name {
+            Some(name) => self
+                .get(name)
+                .ok_or_else(|| AppError::UnknownKey(name.to_string()).into()),
+            None => Ok(self.primary()),
+        }
+    }
+
+    /// Select the keys a signing request should be signed with.
+    ///
+    /// A named key is used on its own. Otherwise, in rotation mode every key
+    /// that can sign is used, and outside of it only the primary key.
+    pub fn select_signing(&self, name: Option<&str>) -> Result<Vec<&KeyEntry>> {
+        if name.is_none() && self.rotation {
+            return Ok(self
+                .keys
+                .values()
+                .filter(|key| key.status.can_sign())
+                .collect());
+        }
+
+        let key = self.select(name)?;
+        if !key.status.can_sign() {
+            return Err(AppError::KeyCannotSign(key.name.clone(), key.status).into());
+        }
+
+        Ok(vec![key])
+    }
+
+    /// Read the secret key file contents of every key selected by
+    /// [`Keyring::select_signing`], if the caller may use them.
+    pub async fn signing_secrets(
+        &self,
+        name: Option<&str>,
+        caller: &Caller,
+    ) -> Result<Vec<String>> {
+        let keys = self.select_signing(name)?;
+        caller.check_keys(keys.iter().copied())?;
+
+        let mut secrets = Vec::new();
+        for key in keys {
+            secrets.push(key.secret_contents().await?);
+        }
+
+        Ok(secrets)
+    }
+
+    /// Every key whose signatures clients should currently accept.
+    pub fn trusted(&self) -> impl Iterator<Item = &KeyEntry> {
+        self.keys.values().filter(|key| key.status.is_trusted())
+    }
+}
+
+pub async fn read_secret_key_file(secret_key_path: &Path) -> Result<String> {
+    let secret_key_path_contents = tokio::fs::read_to_string(&secret_key_path)
+        .await
+        .wrap_err_with(|| format!("Failed to read {}", secret_key_path.display()))?;
+
+    Ok(secret_key_path_contents.trim().to_owned())
+}
@@ -0,0 +1,396 @@
+//! Accepting connections, over TCP, TLS or a Unix socket, and knowing who is on the other end.
+
+pub mod systemd;
+pub mod tls;
+
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::extract::connect_info::Connected;
+use color_eyre::eyre::WrapErr;
+use hyper::server::accept::Accept;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+use crate::error::Result;
+
+use self::systemd::ActivatedSocket;
+use self::tls::{ClientCertificate, TlsAcceptor};
+
+/// How many handshaken connections may wait for hyper to pick them up.
+const BACKLOG: usize = 64;
+
+/// Where to listen for connections.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl std::str::FromStr for ListenAddr {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => Ok(ListenAddr::Tcp(s.parse()?)),
+        }
+    }
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Who is on the other end of a connection.
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub addr: PeerAddr,
+    /// The verified client certificate, if the peer presented one
+    pub certificate: Option<Arc<ClientCertificate>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// The credentials of the process that connected, from `SO_PEERCRED`
+    Unix {
+        uid: u32,
+        gid: u32,
+    },
+}
+
+impl Peer {
+    /// Where the connection came from, for logs.
+    pub fn source(&self) -> String {
+        match self.addr {
+            PeerAddr::Tcp(addr) => addr.ip().to_string(),
+            PeerAddr::Unix { uid, .. } => format!("uid {uid}"),
+        }
+    }
+
+    /// Who the peer is, if the connection itself says.
+    pub fn identity(&self) -> Option<String> {
+        match (&self.certificate, self.addr) {
+            (Some(certificate), _) => Some(format!("certificate {}", certificate.identity())),
+            (None, PeerAddr::Unix { uid, .. }) => Some(format!("uid {uid}")),
+            (None, PeerAddr::Tcp(_)) => None,
+        }
+    }
+}
+
+impl Connected<&Connection> for Peer {
+    fn connect_info(connection: &Connection) -> Self {
+        connection.peer.clone()
+    }
+}
+
+pub struct Connection {
+    stream: Stream,
+    peer: Peer,
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+    Unix(UnixStream),
+}
+
+/// Accepts connections in the background, so slow TLS handshakes don't hold up anyone else.
+pub struct Listener {
+    local_addrs: Vec<ListenAddr>,
+    connections: mpsc::Receiver<Connection>,
+}
+
+enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    #[tracing::instrument(skip(tls))]
+    pub async fn bind(addr: &ListenAddr, tls: Option<Arc<TlsAcceptor>>) -> Result<Self> {
+        let socket = match addr {
+            ListenAddr::Tcp(addr) => Socket::Tcp(TcpListener::bind(addr).await?),
+            ListenAddr::Unix(path) => {
+                if tls.is_some() {
+                    return Err(color_eyre::eyre::eyre!(
+                        "TLS isn't supported when listening on a Unix socket"
+                    )
+                    .into());
+                }
+
+                // Clean up after a previous run, which can't have unlinked its socket if it crashed,
+                // but don't take it over from one that's still listening on it
+                let is_socket = std::fs::symlink_metadata(path)
+                    .is_ok_and(|metadata| metadata.file_type().is_socket());
+                if is_socket {
+                    match UnixStream::connect(path).await {
+                        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                            std::fs::remove_file(path)?
+                        }
+                        _ => {
+                            return Err(color_eyre::eyre::eyre!(
+                                "Failed to listen on {}: address in use",
+                                path.display()
+                            )
+                            .into())
+                        }
+                    }
+                }
+                Socket::Unix(
+                    UnixListener::bind(path)
+                        .wrap_err_with(|| format!("Failed to listen on {}", path.display()))?,
+                )
+            }
+        };
+
+        Self::serve(vec![socket], tls)
+    }
+
+    /// Take over the sockets systemd passed us, or `None` if we weren't socket activated.
+    pub fn activated(
+        env: Option<systemd::ListenEnv>,
+        tls: Option<Arc<TlsAcceptor>>,
+    ) -> Result<Option<Self>> {
+        let Some(env) = env else {
+            return Ok(None);
+        };
+        match systemd::take_sockets(env)? {
+            Some(sockets) => Self::from_activated(sockets, tls).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Listen on sockets that were opened for us, by name.
+    ///
+    /// TLS is only used on TCP sockets.
+    pub fn from_activated(
+        sockets: Vec<(String, ActivatedSocket)>,
+        tls: Option<Arc<TlsAcceptor>>,
+    ) -> Result<Self> {
+        if sockets.is_empty() {
+            return Err(color_eyre::eyre::eyre!("No sockets were passed to us").into());
+        }
+
+        let sockets = sockets
+            .into_iter()
+            .map(|(name, socket)| {
+                tracing::debug!("taking over socket '{name}'");
+                Self::from_std(socket)
+            })
+            .collect::<Result<_>>()?;
+
+        Self::serve(sockets, tls)
+    }
+
+    fn from_std(socket: ActivatedSocket) -> Result<Socket> {
+        Ok(match socket {
+            ActivatedSocket::Tcp(socket) => Socket::Tcp(TcpListener::from_std(socket)?),
+            ActivatedSocket::Unix(socket) => Socket::Unix(UnixListener::from_std(socket)?),
+        })
+    }
+
+    fn serve(sockets: Vec<Socket>, tls: Option<Arc<TlsAcceptor>>) -> Result<Self> {
+        let (sender, connections) = mpsc::channel(BACKLOG);
+
+        let mut local_addrs = Vec::new();
+        for socket in sockets {
+            match socket {
+                Socket::Tcp(listener) => {
+                    local_addrs.push(ListenAddr::Tcp(listener.local_addr()?));
+                    tokio::spawn(accept_tcp(listener, tls.clone(), sender.clone()));
+                }
+                Socket::Unix(listener) => {
+                    let local_addr = listener.local_addr()?;
+                    let path = local_addr.as_pathname().unwrap_or(Path::new("<unnamed>"));
+                    local_addrs.push(ListenAddr::Unix(path.to_path_buf()));
+                    tokio::spawn(accept_unix(listener, sender.clone()));
+                }
+            }
+        }
+
+        Ok(Self {
+            local_addrs,
+            connections,
+        })
+    }
+
+    pub fn local_addrs(&self) -> &[ListenAddr] {
+        &self.local_addrs
+    }
+}
+
+impl Accept for Listener {
+    type Conn = Connection;
+    type Error = std::convert::Infallible;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.connections
+            .poll_recv(cx)
+            .map(|connection| connection.map(Ok))
+    }
+}
+
+async fn accept_tcp(
+    listener: TcpListener,
+    tls: Option<Arc<TlsAcceptor>>,
+    sender: mpsc::Sender<Connection>,
+) {
+    while !sender.is_closed() {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Probably out of file descriptors, give some connections time to close
+                tracing::error!("failed to accept a connection: {err}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let tls = tls.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let connection = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok((stream, certificate)) => Connection {
+                        stream: Stream::Tls(Box::new(stream)),
+                        peer: Peer {
+                            addr: PeerAddr::Tcp(addr),
+                            certificate: certificate.map(Arc::new),
+                        },
+                    },
+                    Err(err) => {
+                        tracing::debug!("TLS handshake with {addr} failed: {err:?}");
+                        return;
+                    }
+                },
+                None => Connection {
+                    stream: Stream::Tcp(stream),
+                    peer: Peer {
+                        addr: PeerAddr::Tcp(addr),
+                        certificate: None,
+                    },
+                },
+            };
+
+            // The server is shutting down if nobody is receiving
+            let _ = sender.send(connection).await;
+        });
+    }
+}
+
+async fn accept_unix(listener: UnixListener, sender: mpsc::Sender<Connection>) {
+    while !sender.is_closed() {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                tracing::error!("failed to accept a connection: {err}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        // Authorization relies on knowing who connected, so never accept anyone we can't identify
+        let credentials = match stream.peer_cred() {
+            Ok(credentials) => credentials,
+            Err(err) => {
+                tracing::error!("failed to get the credentials of a Unix socket peer: {err}");
+                continue;
+            }
+        };
+
+        let connection = Connection {
+            stream: Stream::Unix(stream),
+            peer: Peer {
+                addr: PeerAddr::Unix {
+                    uid: credentials.uid(),
+                    gid: credentials.gid(),
+                },
+                certificate: None,
+            },
+        };
+        if sender.send(connection).await.is_err() {
+            break;
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match &self.stream {
+            Stream::Tcp(stream) => stream.is_write_vectored(),
+            Stream::Tls(stream) => stream.is_write_vectored(),
+            Stream::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! Taking over listening sockets passed by systemd socket activation.
+// Adapted from:
+// https://github.com/systemd/systemd/blob/v254/src/libsystemd/sd-daemon/sd-daemon.c
+
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+
+use color_eyre::eyre::WrapErr;
+
+use crate::error::Result;
+
+/// The first file descriptor systemd passes, after stdin, stdout and stderr.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub enum ActivatedSocket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// What systemd said about the sockets it passed us, in `LISTEN_PID`, `LISTEN_FDS` and
+/// `LISTEN_FDNAMES`.
+pub struct ListenEnv {
+    pid: String,
+    fds: String,
+    names: String,
+}
+
+impl ListenEnv {
+    /// Read and clear the variables, or `None` if systemd didn't set them.
+    ///
+    /// Clearing the environment isn't safe once other threads may be reading it, so this has to
+    /// happen before the runtime starts.
+    pub fn take() -> Option<Self> {
+        let (Ok(pid), Ok(fds)) = (std::env::var("LISTEN_PID"), std::env::var("LISTEN_FDS")) else {
+            return None;
+        };
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+
+        // Anything we spawn shouldn't think the sockets are meant for it
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+
+        Some(Self { pid, fds, names })
+    }
+}
+
+/// Take the sockets systemd passed us, or `None` if they weren't meant for us.
+pub fn take_sockets(env: ListenEnv) -> Result<Option<Vec<(String, ActivatedSocket)>>> {
+    let ListenEnv { pid, fds, names } = env;
+    if pid.parse::<u32>().wrap_err("LISTEN_PID wasn't a pid")? != std::process::id() {
+        return Ok(None);
+    }
+    let count: RawFd = fds.parse().wrap_err("LISTEN_FDS wasn't a number")?;
+
+    let mut names = names.split(':');
+    let mut sockets = Vec::new();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        let name = names.next().unwrap_or("unknown").to_string();
+        // SAFETY: systemd passed us these, and nothing else in the process knows about them
+        let socket = unsafe { socket_from_fd(fd) }
+            .wrap_err_with(|| format!("Failed to take over socket '{name}' (fd {fd})"))?;
+        sockets.push((name, socket));
+    }
+
+    Ok(Some(sockets))
+}
+
+/// Work out whether `fd` is a TCP or Unix socket.
+///
+/// # Safety
+///
+/// `fd` must be an open socket that nothing else owns.
+pub unsafe fn socket_from_fd(fd: RawFd) -> io::Result<ActivatedSocket> {
+    // Inherited file descriptors aren't close-on-exec, so they'd leak into `nix` and friends
+    if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let unix = UnixListener::from_raw_fd(fd);
+    if unix.local_addr().is_ok() {
+        unix.set_nonblocking(true)?;
+        return Ok(ActivatedSocket::Unix(unix));
+    }
+
+    let tcp = TcpListener::from_raw_fd(unix.into_raw_fd());
+    tcp.local_addr()?;
+    tcp.set_nonblocking(true)?;
+
+    Ok(ActivatedSocket::Tcp(tcp))
+}
@@ -0,0 +1,210 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use color_eyre::eyre::WrapErr;
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use x509_parser::extensions::GeneralName;
+
+use crate::cli::tls::TlsArgs;
+use crate::error::Result;
+
+/// Clients get this long to finish the handshake, so they can't hold a connection slot forever.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts TLS connections, with a configuration that can be reloaded from disk.
+pub struct TlsAcceptor {
+    args: TlsArgs,
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+impl TlsAcceptor {
+    pub fn load(args: TlsArgs) -> Result<Self> {
+        let config = server_config(&args)?;
+
+        Ok(Self {
+            args,
+            config: RwLock::new(Arc::new(config)),
+        })
+    }
+
+    /// Read the certificates and key again, keeping the old ones if they're broken.
+    #[tracing::instrument(skip_all)]
+    pub fn reload(&self) -> Result<()> {
+        let config = server_config(&self.args)?;
+        *self
+            .config
+            .write()
+            .map_err(|_| color_eyre::eyre::eyre!("TLS configuration was poisoned"))? =
+            Arc::new(config);
+        tracing::info!("reloaded TLS certificates");
+
+        Ok(())
+    }
+
+    /// Reload whenever we get SIGHUP.
+    pub fn reload_on_sighup(self: &Arc<Self>) -> Result<()> {
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        let acceptor = self.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                if let Err(err) = acceptor.reload() {
+                    tracing::error!("failed to reload TLS certificates: {err:?}");
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    pub async fn accept(
+        &self,
+        stream: TcpStream,
+    ) -> Result<(TlsStream<TcpStream>, Option<ClientCertificate>)> {
+        let config = self
+            .config
+            .read()
+            .map_err(|_| color_eyre::eyre::eyre!("TLS configuration was poisoned"))?
+            .clone();
+
+        let stream = tokio::time::timeout(
+            HANDSHAKE_TIMEOUT,
+            tokio_rustls::TlsAcceptor::from(config).accept(stream),
+        )
+        .await
+        .wrap_err("TLS handshake timed out")??;
+
+        // rustls has already verified the chain against the client CA by now
+        let certificate = match stream.get_ref().1.peer_certificates() {
+            Some([leaf, ..]) => Some(ClientCertificate::parse(&leaf.0)?),
+            _ => None,
+        };
+
+        Ok((stream, certificate))
+    }
+}
+
+/// The names in a verified client certificate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// DNS, email and URI subject alternative names, then subject common names
+    pub names: Vec<String>,
+}
+
+impl ClientCertificate {
+    pub fn parse(der: &[u8]) -> Result<Self> {
+        let (_, certificate) = x509_parser::parse_x509_certificate(der)
+            .map_err(|err| color_eyre::eyre::eyre!("Failed to parse client certificate: {err}"))?;
+
+        let mut names = Vec::new();
+        if let Some(san) = certificate.subject_alternative_name()? {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => names.push(name.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        for common_name in certificate.subject().iter_common_name() {
+            if let Ok(common_name) = common_name.as_str() {
+                names.push(common_name.to_string());
+            }
+        }
+
+        if names.is_empty() {
+            return Err(color_eyre::eyre::eyre!(
+                "Client certificate '{}' has no usable name",
+                certificate.subject()
+            )
+            .into());
+        }
+
+        Ok(Self { names })
+    }
+
+    /// The name to call the client by.
+    pub fn identity(&self) -> &str {
+        &self.names[0]
+    }
+}
+
+fn server_config(args: &TlsArgs) -> Result<ServerConfig> {
+    let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) else {
+        return Err(color_eyre::eyre::eyre!("TLS needs both --tls-cert and --tls-key").into());
+    };
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &args.tls_client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(client_ca)? {
+                roots.add(&certificate).wrap_err_with(|| {
+                    format!("Invalid client CA certificate in {}", client_ca.display())
+                })?;
+            }
+
+            if args.tls_require_client_cert {
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            } else {
+                builder.with_client_cert_verifier(
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+                )
+            }
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(read_certificates(cert)?, read_private_key(key)?)
+        .wrap_err("Invalid TLS certificate or key")?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(
+        File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?,
+    );
+    let certificates = rustls_pemfile::certs(&mut reader)
+        .wrap_err_with(|| format!("Failed to read certificates from {}", path.display()))?;
+
+    if certificates.is_empty() {
+        return Err(
+            color_eyre::eyre::eyre!("No certificates were found in {}", path.display()).into(),
+        );
+    }
+
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(
+        File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?,
+    );
+
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader)
+            .wrap_err_with(|| format!("Failed to read a private key from {}", path.display()))?;
+        match item {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => {
+                return Err(color_eyre::eyre::eyre!(
+                    "No private key was found in {}",
+                    path.display()
+                )
+                .into())
+            }
+        }
+    }
+}
@@ -1,59 +1,318 @@
+mod audit;
+mod auth;
+mod batch;
 mod cli;
+mod client_addr;
 mod error;
+mod keyring;
+mod listener;
+mod metrics;
 mod nix;
+mod policy;
+mod rate_limit;
 #[cfg(test)]
 mod test;
 mod trace_layer;
+mod transparency;
 
+use std::collections::BTreeMap;
 use std::io::IsTerminal;
-use std::net::SocketAddr;
-use std::path::{Path, PathBuf};
+use std::num::NonZeroU32;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use axum::extract::State;
-use axum::response::IntoResponse;
+use axum::extract::{Extension, Query, State};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::Router;
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine as _;
 use clap::Parser;
-use color_eyre::eyre::WrapErr;
 use dryoc::classic::crypto_sign_ed25519::Signature;
 use dryoc::constants::{
     CRYPTO_SIGN_ED25519_BYTES, CRYPTO_SIGN_ED25519_PUBLICKEYBYTES,
     CRYPTO_SIGN_ED25519_SECRETKEYBYTES,
 };
 use dryoc::sign::SigningKeyPair;
-use tokio::process::Command;
 use tower_http::trace::TraceLayer;
 
+use crate::audit::AuditLog;
+use crate::auth::{Authorizer, Caller};
+use crate::batch::BatchFormat;
+use crate::client_addr::TrustedProxies;
 use crate::error::AppError;
 use crate::error::Result;
+use crate::keyring::{KeySelection, Keyring};
+use crate::listener::{systemd, ListenAddr, Listener, Peer};
+use crate::nix::PathInfoSource;
+use crate::policy::Policy;
+use crate::rate_limit::RateLimits;
+use crate::transparency::{TransparencyLog, TreeHead};
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct SignStorePathQuery {
+    /// The name of the key to sign with, defaults to the primary key
+    key: Option<String>,
+    /// Sign the entire closure of the store paths, responding with a map of store path to signature
+    #[serde(default)]
+    recursive: bool,
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VerifyRequest {
+    /// The fingerprint the signatures were made over
+    fingerprint: Option<String>,
+    /// A store path to compute the fingerprint from, instead of `fingerprint`
+    store_path: Option<String>,
+    /// Signatures in `name:base64sig` form
+    signatures: Vec<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, serde_derive::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Verification {
+    /// Signatures made over the fingerprint by a key in the keyring
+    valid: Vec<String>,
+    /// Signatures from keys that aren't in the keyring
+    unknown_key: Vec<String>,
+    /// Signatures claiming to be from a key in the keyring that don't verify
+    forged: Vec<String>,
+    /// Signatures that aren't in `name:base64sig` form
+    malformed: Vec<String>,
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct PublicKeyQuery {
+    /// The name of the key to show, defaults to the primary key
+    key: Option<String>,
+    /// List every trusted public key instead of a single one
+    #[serde(default)]
+    trusted: bool,
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InclusionProofQuery {
+    /// The index of the leaf to prove is in the tree
+    leaf_index: Option<u64>,
+    /// The base64 hash of the leaf to prove is in the tree, instead of `leaf_index`
+    leaf_hash: Option<String>,
+    /// The size of the tree to prove it's in, defaults to the current size
+    tree_size: Option<u64>,
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct ConsistencyProofQuery {
+    /// The size of the older tree
+    first: u64,
+    /// The size of the newer tree, defaults to the current size
+    second: Option<u64>,
+}
 
 type AppContext = Arc<AppContextInner>;
 
 struct AppContextInner {
-    secret_key_path: PathBuf,
-    public_key: String,
+    keyring: Keyring,
+    path_info_source: Box<dyn PathInfoSource>,
+    /// `None` if every caller may do anything
+    authorizer: Option<Authorizer>,
+    policy: Policy,
+    /// Sign anything sent to `/sign`, not just fingerprints
+    allow_malformed_fingerprints: bool,
+    /// Only sign fingerprints matching a local store path
+    verify_fingerprints: bool,
+    audit_log: Option<AuditLog>,
+    transparency_log: Option<TransparencyLog>,
+    trusted_proxies: TrustedProxies,
+    /// `None` if clients may make as many requests as they like
+    rate_limits: Option<RateLimits>,
 }
 
 impl AppContextInner {
-    async fn get_secret_contents(secret_key_path: &Path) -> Result<String> {
-        let secret_key_path_contents = tokio::fs::read_to_string(&secret_key_path)
-            .await
-            .wrap_err_with(|| format!("Failed to read {}", secret_key_path.display()))?;
+    async fn new(cli: &cli::Cli) -> Result<Self> {
+        let keyring_args = &cli.keyring;
+        let path_info_args = &cli.path_info;
+
+        let keyring = Keyring::load(keyring_args).await?;
+        let path_info_source = path_info_args.source()?;
+        tracing::debug!(
+            "getting path info using {}",
+            path_info_args.path_info_backend
+        );
+
+        let authorizer = match &cli.auth_file {
+            Some(auth_file) => Some(Authorizer::load(auth_file).await?),
+            None => {
+                tracing::warn!("no --auth-file was given, anyone who can connect may sign");
+                None
+            }
+        };
+
+        let policy = match &cli.policy_file {
+            Some(policy_file) => Policy::load(policy_file).await?,
+            None => Policy::allow_all(),
+        };
+
+        let audit_log = match &cli.audit_log {
+            Some(audit_log) => Some(AuditLog::open(audit_log).await?),
+            None => None,
+        };
+
+        let transparency_log = match &cli.transparency_log {
+            Some(transparency_log) => {
+                let secret_key_path = match &cli.transparency_log_key_file {
+                    Some(key_file) => key_file.clone(),
+                    None => keyring.primary().secret_key_path.clone(),
+                };
+                let secret_key = keyring::read_secret_key_file(&secret_key_path).await?;
+                tracing::info!(
+                    "signing transparency log tree heads with {}",
+                    secret_key_to_public_key(&secret_key)?
+                );
+
+                Some(TransparencyLog::open(transparency_log, secret_key_path).await?)
+            }
+            None => None,
+        };
+
+        let rate_limits = cli.rate_limit.map(|rate| {
+            // A second's worth, unless told otherwise
+            let burst = cli
+                .rate_limit_burst
+                .map_or_else(|| (rate.ceil() as u32).max(1), NonZeroU32::get);
+            tracing::info!("limiting each client to {rate} requests a second, {burst} at once");
+            RateLimits::new(rate, burst)
+        });
 
-        Ok(secret_key_path_contents.trim().to_owned())
+        Ok(Self {
+            keyring,
+            path_info_source,
+            authorizer,
+            policy,
+            allow_malformed_fingerprints: cli.allow_malformed_fingerprints,
+            verify_fingerprints: cli.verify_fingerprints,
+            audit_log,
+            transparency_log,
+            trusted_proxies: TrustedProxies::new(
+                cli.trusted_proxies.clone(),
+                cli.client_addr_header,
+            ),
+            rate_limits,
+        })
     }
 
-    async fn new(secret_key_path: &Path) -> Result<Self> {
-        let secret_key_path_contents = Self::get_secret_contents(secret_key_path).await?;
-        let public_key = secret_key_to_public_key(&secret_key_path_contents)?;
+    /// Record signatures made on `route` in the metrics, and the audit log and transparency log
+    /// if they're kept.
+    async fn record_signatures<'a>(
+        &self,
+        caller: &Caller,
+        route: &str,
+        signed: impl IntoIterator<Item = (&'a [u8], &'a str)>,
+    ) -> Result<()> {
+        let signed: Vec<_> = signed.into_iter().collect();
+
+        for (_, signatures) in &signed {
+            metrics::metrics().record_signatures(route, signatures);
+        }
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(caller, signed.iter().copied()).await?;
+        }
+        if let Some(transparency_log) = &self.transparency_log {
+            let leaves = signed
+                .iter()
+                .flat_map(|(fingerprint, signatures)| {
+                    signatures
+                        .lines()
+                        .map(|signature| transparency::leaf(signature, fingerprint))
+                })
+                .collect();
+            transparency_log.append(leaves).await?;
+        }
+
+        Ok(())
+    }
 
-        Ok(Self {
-            secret_key_path: secret_key_path.to_path_buf(),
-            public_key,
-        })
+    /// Record the signatures `sign_path_infos` made, like `record_signatures`.
+    async fn record_path_info_signatures(
+        &self,
+        caller: &Caller,
+        route: &str,
+        path_infos: &[nix::PathInfo],
+        signatures: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        let fingerprints = path_infos
+            .iter()
+            .map(nix::PathInfo::fingerprint)
+            .collect::<Result<Vec<_>>>()?;
+        let signed = fingerprints
+            .iter()
+            .zip(path_infos)
+            .map(|(fingerprint, path_info)| {
+                (
+                    fingerprint.as_bytes(),
+                    signatures[&path_info.store_path].as_str(),
+                )
+            });
+
+        self.record_signatures(caller, route, signed).await
+    }
+
+    fn transparency_log(&self) -> Result<&TransparencyLog> {
+        self.transparency_log
+            .as_ref()
+            .ok_or_else(|| AppError::TransparencyLogDisabled.into())
+    }
+
+    /// Make sure we're being asked to sign a fingerprint, rather than arbitrary data, and that
+    /// it matches the local store if `verify_fingerprints` is set.
+    async fn check_fingerprint(&self, fingerprint: &[u8]) -> Result<()> {
+        let parsed = std::str::from_utf8(fingerprint)
+            .map_err(|_| AppError::MalformedFingerprint(String::from("not UTF-8")).into())
+            .and_then(nix::Fingerprint::parse);
+        let fingerprint = match parsed {
+            Ok(fingerprint) => fingerprint,
+            // There's no store path to hold a policy to, so only when there's no policy
+            Err(_) if self.allow_malformed_fingerprints && self.policy.allows_everything() => {
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+
+        let claimed = nix::PathInfo::from(&fingerprint);
+        let path_info = self.verify_path_info(fingerprint, claimed).await?;
+        self.policy.check(&path_info)
+    }
+
+    /// The path info to hold to the policy when signing `fingerprint`: the local store's if
+    /// `verify_fingerprints` is set and it matches, or else the one the caller `claimed`, without
+    /// its deriver.
+    async fn verify_path_info(
+        &self,
+        fingerprint: nix::Fingerprint,
+        claimed: nix::PathInfo,
+    ) -> Result<nix::PathInfo> {
+        if !self.verify_fingerprints {
+            // The deriver isn't part of the fingerprint, so nothing vouches for a claimed one
+            return Ok(nix::PathInfo {
+                deriver: None,
+                ..claimed
+            });
+        }
+
+        let path_info = self
+            .path_info_source
+            .require_path_info(&fingerprint.store_path)
+            .await?;
+        if let Some(reason) = fingerprint.mismatch(&path_info) {
+            return Err(AppError::FingerprintMismatch {
+                store_path: fingerprint.store_path,
+                reason,
+            }
+            .into());
+        }
+        Ok(path_info)
     }
 }
 
@@ -66,7 +325,7 @@ fn secret_key_to_public_key(secret_key_file_contents: &str) -> Result<String> {
         [u8; CRYPTO_SIGN_ED25519_SECRETKEYBYTES],
     > = SigningKeyPair::from_secret_key(secret_key);
 
-    let public_key_base64 = STANDARD.encode(&signing_pair.public_key);
+    let public_key_base64 = STANDARD.encode(signing_pair.public_key);
     let public_key = format!("{key_name}:{public_key_base64}");
 
     Ok(public_key)
@@ -88,8 +347,17 @@ fn secret_key_from_contents(
     Ok((key_name.to_string(), secret_key))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
+    // Before there are any other threads to read the environment as it's changed
+    let listen_env = systemd::ListenEnv::take();
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(listen_env))
+}
+
+async fn run(listen_env: Option<systemd::ListenEnv>) -> Result<()> {
     color_eyre::config::HookBuilder::default()
         .theme(if !std::io::stderr().is_terminal() {
             // Don't attempt color
@@ -100,9 +368,18 @@ async fn main() -> Result<()> {
         .install()?;
 
     let cli = cli::Cli::parse();
+    if let Err(err) = cli.validate() {
+        err.exit();
+    }
     cli.instrumentation.setup()?;
 
-    let ctx = AppContextInner::new(&cli.secret_key_file).await?;
+    if let Some(cli::Command::VerifyAuditLog { path }) = &cli.command {
+        let head = audit::verify(path).await?;
+        println!("{} records, ending in {}", head.records, head.hash);
+        return Ok(());
+    }
+
+    let ctx = AppContextInner::new(&cli).await?;
     let ctx = Arc::new(ctx);
 
     let trace_layer = TraceLayer::new_for_http()
@@ -110,17 +387,77 @@ async fn main() -> Result<()> {
         .on_request(trace_layer::trace_layer_on_request)
         .on_response(trace_layer::trace_layer_on_response);
 
-    let app = Router::new()
+    let mut router = Router::new()
         .route("/sign", post(sign))
+        .route("/sign/batch", post(sign_batch))
         .route("/sign-store-path", post(sign_store_path))
+        .route("/sign-store-path/batch", post(sign_store_path_batch))
+        .route("/sign-narinfo", post(sign_narinfo))
         .route("/publickey", get(public_key))
+        .route("/verify", post(verify))
+        .route("/log/tree-head", get(tree_head))
+        .route("/log/inclusion-proof", get(inclusion_proof))
+        .route("/log/consistency-proof", get(consistency_proof));
+    if cli.metrics_bind.is_none() {
+        router = router.route("/metrics", get(metrics::serve_metrics));
+    }
+    let app = router
+        // Inside `authorize`, which says who the caller is
+        .route_layer(axum::middleware::from_fn_with_state(
+            ctx.clone(),
+            rate_limit::limit_caller,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            ctx.clone(),
+            auth::authorize,
+        ))
+        // Outside it, so callers failing to authenticate are limited too
+        .route_layer(axum::middleware::from_fn_with_state(
+            ctx.clone(),
+            rate_limit::limit_client_addr,
+        ))
         .with_state(ctx.clone())
         .fallback(not_found)
-        .layer(trace_layer);
+        .layer(axum::middleware::from_fn(metrics::track))
+        .layer(axum::middleware::from_fn(error::negotiate))
+        .layer(trace_layer)
+        .layer(axum::middleware::from_fn(trace_layer::assign_request_id))
+        // Outside the trace layer, so the client address can be logged
+        .layer(axum::middleware::from_fn_with_state(
+            ctx.clone(),
+            client_addr::resolve,
+        ));
+
+    if let Some(metrics_bind) = cli.metrics_bind {
+        let metrics_app = Router::new().route("/metrics", get(metrics::serve_metrics));
+        let metrics_server = axum::Server::try_bind(&metrics_bind)?;
+        tracing::info!("serving metrics on {metrics_bind}");
+        tokio::spawn(async move {
+            if let Err(err) = metrics_server.serve(metrics_app.into_make_service()).await {
+                tracing::error!("metrics server failed: {err}");
+            }
+        });
+    }
 
-    tracing::info!("listening on {}", &cli.bind);
-    axum::Server::bind(&cli.bind)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+    let tls = cli.tls.acceptor()?;
+    if let Some(tls) = &tls {
+        tls.reload_on_sighup()?;
+    }
+    let listener = match Listener::activated(listen_env, tls.clone())? {
+        Some(listener) => listener,
+        None => Listener::bind(&cli.listen_addr(), tls.clone()).await?,
+    };
+
+    for local_addr in listener.local_addrs() {
+        match local_addr {
+            ListenAddr::Tcp(_) if tls.is_some() => {
+                tracing::info!("listening on https://{local_addr}")
+            }
+            _ => tracing::info!("listening on {local_addr}"),
+        }
+    }
+    axum::Server::builder(listener)
+        .serve(app.into_make_service_with_connect_info::<Peer>())
         .await?;
 
     Ok(())
@@ -132,72 +469,401 @@ async fn not_found() -> impl IntoResponse {
 }
 
 #[tracing::instrument(skip_all)]
-async fn public_key(State(state): State<AppContext>) -> impl IntoResponse {
-    state.public_key.clone()
+async fn public_key(
+    State(state): State<AppContext>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<PublicKeyQuery>,
+) -> Result<impl IntoResponse> {
+    if query.trusted {
+        // Only the keys the caller may use, so as not to tell them about any others
+        let public_keys: Vec<&str> = state
+            .keyring
+            .trusted()
+            .filter(|key| caller.may_use(key))
+            .map(|key| key.public_key.as_str())
+            .collect();
+
+        return Ok(public_keys.join("\n"));
+    }
+
+    let key = state.keyring.select(query.key.as_deref())?;
+    caller.check_keys([key])?;
+
+    Ok(key.public_key.clone())
 }
 
-#[tracing::instrument(skip_all)]
+#[tracing::instrument(skip_all, fields(recursive))]
 async fn sign_store_path(
     State(state): State<AppContext>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<SignStorePathQuery>,
     store_path: String,
-) -> Result<impl IntoResponse> {
-    let store_path = PathBuf::from(store_path);
-
-    if !store_path.exists() {
-        return Err(AppError::MissingStorePath(store_path).into());
-    }
-
-    tracing::debug!(
-        "getting path info from store path '{}'",
-        store_path.display()
-    );
-    let nix_path_info_output = Command::new("nix")
-        .args(["--extra-experimental-features", "nix-command"])
-        .arg("path-info")
-        .arg("--json")
-        .arg(&store_path)
-        .output()
+) -> Result<Response> {
+    tracing::Span::current().record("recursive", query.recursive);
+    let nix_path_infos = state
+        .path_info_source
+        .query_path_infos(&[store_path], query.recursive)
+        .await?;
+    state.policy.check_all(&nix_path_infos)?;
+
+    let encoded_secret_keys = state
+        .keyring
+        .signing_secrets(query.key.as_deref(), &caller)
         .await?;
 
-    let nix_path_infos: Vec<nix::PathInfo> = serde_json::from_slice(&nix_path_info_output.stdout)?;
+    if query.recursive {
+        let signatures = sign_path_infos(&encoded_secret_keys, &nix_path_infos)?;
+        state
+            .record_path_info_signatures(&caller, "/sign-store-path", &nix_path_infos, &signatures)
+            .await?;
+        return Ok(axum::Json(signatures).into_response());
+    }
+
     let nix_path_info = nix_path_infos
         .first()
         .ok_or_else(|| color_eyre::eyre::eyre!("Should have been a first path info"))?;
 
-    let encoded_secret_key = AppContextInner::get_secret_contents(&state.secret_key_path).await?;
-
     let fingerprint = nix_path_info.fingerprint()?;
+    let signatures = sign_fingerprint(&encoded_secret_keys, fingerprint.clone().into()).await?;
+    state
+        .record_signatures(
+            &caller,
+            "/sign-store-path",
+            [(fingerprint.as_bytes(), signatures.as_str())],
+        )
+        .await?;
 
-    sign_fingerprint(&encoded_secret_key, fingerprint.into()).await
+    Ok(signatures.into_response())
+}
+
+#[tracing::instrument(skip_all, fields(recursive))]
+async fn sign_store_path_batch(
+    State(state): State<AppContext>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<SignStorePathQuery>,
+    headers: HeaderMap,
+    body: hyper::body::Bytes,
+) -> Result<impl IntoResponse> {
+    tracing::Span::current().record("recursive", query.recursive);
+    let store_paths = BatchFormat::from_headers(&headers).parse(&body)?;
+    let nix_path_infos = state
+        .path_info_source
+        .query_path_infos(&store_paths, query.recursive)
+        .await?;
+    state.policy.check_all(&nix_path_infos)?;
+
+    let encoded_secret_keys = state
+        .keyring
+        .signing_secrets(query.key.as_deref(), &caller)
+        .await?;
+    let signatures = sign_path_infos(&encoded_secret_keys, &nix_path_infos)?;
+    state
+        .record_path_info_signatures(
+            &caller,
+            "/sign-store-path/batch",
+            &nix_path_infos,
+            &signatures,
+        )
+        .await?;
+
+    Ok(axum::Json(signatures))
+}
+
+/// Sign every path info, parsing the secret keys only once.
+fn sign_path_infos(
+    secret_key_file_contents: &[impl AsRef<str>],
+    nix_path_infos: &[nix::PathInfo],
+) -> Result<BTreeMap<String, String>> {
+    let secret_keys = parse_secret_keys(secret_key_file_contents)?;
+
+    nix_path_infos
+        .iter()
+        .map(|nix_path_info| {
+            let fingerprint = nix_path_info.fingerprint()?;
+            let signature = sign_with_keys(&secret_keys, fingerprint.as_bytes())?;
+
+            Ok((nix_path_info.store_path.clone(), signature))
+        })
+        .collect()
 }
 
 #[tracing::instrument(skip_all)]
 async fn sign(
     State(state): State<AppContext>,
+    Extension(caller): Extension<Caller>,
+    Query(selection): Query<KeySelection>,
     fingerprint: hyper::body::Bytes,
 ) -> Result<impl IntoResponse> {
-    let encoded_secret_key = AppContextInner::get_secret_contents(&state.secret_key_path).await?;
+    state.check_fingerprint(&fingerprint).await?;
+
+    let encoded_secret_keys = state
+        .keyring
+        .signing_secrets(selection.key.as_deref(), &caller)
+        .await?;
+
+    let signatures = sign_fingerprint(&encoded_secret_keys, fingerprint.clone()).await?;
+    state
+        .record_signatures(&caller, "/sign", [(&fingerprint[..], signatures.as_str())])
+        .await?;
+
+    Ok(signatures)
+}
+
+#[tracing::instrument(skip_all)]
+async fn sign_batch(
+    State(state): State<AppContext>,
+    Extension(caller): Extension<Caller>,
+    Query(selection): Query<KeySelection>,
+    headers: HeaderMap,
+    body: hyper::body::Bytes,
+) -> Result<impl IntoResponse> {
+    let format = BatchFormat::from_headers(&headers);
+    let fingerprints = format.parse(&body)?;
+    for fingerprint in &fingerprints {
+        state.check_fingerprint(fingerprint.as_bytes()).await?;
+    }
+
+    let encoded_secret_keys = state
+        .keyring
+        .signing_secrets(selection.key.as_deref(), &caller)
+        .await?;
+    let secret_keys = parse_secret_keys(&encoded_secret_keys)?;
+
+    tracing::debug!("signing {} fingerprints", fingerprints.len());
+    let signatures = fingerprints
+        .iter()
+        .map(|fingerprint| sign_with_keys(&secret_keys, fingerprint.as_bytes()))
+        .collect::<Result<Vec<_>>>()?;
+    state
+        .record_signatures(
+            &caller,
+            "/sign/batch",
+            fingerprints
+                .iter()
+                .map(|fingerprint| fingerprint.as_bytes())
+                .zip(signatures.iter().map(String::as_str)),
+        )
+        .await?;
 
-    sign_fingerprint(&encoded_secret_key, fingerprint).await
+    Ok(format.respond(signatures))
+}
+
+#[tracing::instrument(skip_all)]
+async fn sign_narinfo(
+    State(state): State<AppContext>,
+    Extension(caller): Extension<Caller>,
+    Query(selection): Query<KeySelection>,
+    narinfo: String,
+) -> Result<impl IntoResponse> {
+    let nix_path_info = nix::PathInfo::from(&nix::Narinfo::parse(&narinfo)?);
+    tracing::debug!("signing narinfo for '{}'", nix_path_info.store_path);
+    let fingerprint = nix_path_info.fingerprint()?;
+    let path_info = state
+        .verify_path_info(nix::Fingerprint::parse(&fingerprint)?, nix_path_info)
+        .await?;
+    state.policy.check(&path_info)?;
+
+    let encoded_secret_keys = state
+        .keyring
+        .signing_secrets(selection.key.as_deref(), &caller)
+        .await?;
+    let signatures = sign_fingerprint(&encoded_secret_keys, fingerprint.clone().into()).await?;
+    state
+        .record_signatures(
+            &caller,
+            "/sign-narinfo",
+            [(fingerprint.as_bytes(), signatures.as_str())],
+        )
+        .await?;
+
+    let mut signed_narinfo = narinfo;
+    if !signed_narinfo.is_empty() && !signed_narinfo.ends_with('\n') {
+        signed_narinfo.push('\n');
+    }
+    for signature in signatures.lines() {
+        signed_narinfo.push_str(&format!("Sig: {signature}\n"));
+    }
+
+    Ok(signed_narinfo)
 }
 
-// https://github.com/NixOS/nix/blob/ea2f74cbe178d31748d63037e238e3a4a8e02cf3/src/libstore/crypto.cc#L42-L49
+#[tracing::instrument(skip_all)]
+async fn verify(
+    State(state): State<AppContext>,
+    axum::Json(request): axum::Json<VerifyRequest>,
+) -> Result<impl IntoResponse> {
+    let fingerprint = match (request.fingerprint, request.store_path) {
+        (Some(fingerprint), None) => fingerprint,
+        (None, Some(store_path)) => state
+            .path_info_source
+            .require_path_info(&store_path)
+            .await?
+            .fingerprint()?,
+        _ => {
+            return Err(AppError::MalformedRequestBody(String::from(
+                "exactly one of `fingerprint` or `storePath` must be given",
+            ))
+            .into())
+        }
+    };
+
+    let verification =
+        verify_signatures(&state.keyring, fingerprint.as_bytes(), request.signatures)?;
+
+    Ok(axum::Json(verification))
+}
+
+/// Sort signatures by whether they were made over the fingerprint by a key in the keyring.
+fn verify_signatures(
+    keyring: &Keyring,
+    fingerprint: &[u8],
+    signatures: Vec<String>,
+) -> Result<Verification> {
+    let mut verification = Verification::default();
+
+    for signature in signatures {
+        let Some((key_name, signature_base64)) = signature.split_once(':') else {
+            verification.malformed.push(signature);
+            continue;
+        };
+
+        let Some(key) = keyring.get(key_name) else {
+            verification.unknown_key.push(signature);
+            continue;
+        };
+
+        let signature_bytes: Option<Signature> = STANDARD
+            .decode(signature_base64)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok());
+        let Some(signature_bytes) = signature_bytes else {
+            verification.malformed.push(signature);
+            continue;
+        };
+
+        let public_key = key.public_key_bytes()?;
+        match dryoc::classic::crypto_sign::crypto_sign_verify_detached(
+            &signature_bytes,
+            fingerprint,
+            &public_key,
+        ) {
+            Ok(()) => verification.valid.push(signature),
+            Err(_) => verification.forged.push(signature),
+        }
+    }
+
+    Ok(verification)
+}
+
+/// Sign the fingerprint with every given key, producing one `name:signature` line per key.
 #[tracing::instrument(skip_all)]
 async fn sign_fingerprint(
-    secret_key_file_contents: &str,
+    secret_key_file_contents: &[impl AsRef<str>],
     fingerprint: hyper::body::Bytes,
 ) -> Result<String, error::Report> {
-    let (key_name, secret_key) = secret_key_from_contents(secret_key_file_contents)?;
+    let secret_keys = parse_secret_keys(secret_key_file_contents)?;
+
+    sign_with_keys(&secret_keys, &fingerprint)
+}
+
+fn parse_secret_keys(
+    secret_key_file_contents: &[impl AsRef<str>],
+) -> Result<Vec<(String, [u8; CRYPTO_SIGN_ED25519_SECRETKEYBYTES])>> {
+    secret_key_file_contents
+        .iter()
+        .map(|contents| secret_key_from_contents(contents.as_ref()))
+        .collect()
+}
 
-    let mut signature_bytes: Signature = [0u8; CRYPTO_SIGN_ED25519_BYTES];
+// https://github.com/NixOS/nix/blob/ea2f74cbe178d31748d63037e238e3a4a8e02cf3/src/libstore/crypto.cc#L42-L49
+fn sign_with_keys(
+    secret_keys: &[(String, [u8; CRYPTO_SIGN_ED25519_SECRETKEYBYTES])],
+    fingerprint: &[u8],
+) -> Result<String> {
+    let mut signatures = Vec::with_capacity(secret_keys.len());
+
+    for (key_name, secret_key) in secret_keys {
+        let mut signature_bytes: Signature = [0u8; CRYPTO_SIGN_ED25519_BYTES];
+
+        dryoc::classic::crypto_sign::crypto_sign_detached(
+            &mut signature_bytes,
+            fingerprint,
+            secret_key,
+        )?;
+
+        let signature_base64 = STANDARD.encode(signature_bytes);
+        signatures.push(format!("{key_name}:{signature_base64}"));
+    }
 
-    dryoc::classic::crypto_sign::crypto_sign_detached(
-        &mut signature_bytes,
-        &fingerprint,
-        &secret_key,
+    Ok(signatures.join("\n"))
+}
+
+#[tracing::instrument(skip_all)]
+async fn tree_head(State(state): State<AppContext>) -> Result<impl IntoResponse> {
+    let transparency_log = state.transparency_log()?;
+    let (tree_size, root_hash) = transparency_log.root().await;
+    let root_hash = STANDARD.encode(root_hash);
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+
+    let secret_key = keyring::read_secret_key_file(&transparency_log.secret_key_path).await?;
+    let signature = sign_with_keys(
+        &parse_secret_keys(&[secret_key])?,
+        TreeHead::signed_data(tree_size, &root_hash, timestamp).as_bytes(),
     )?;
 
-    let signature_base64 = STANDARD.encode(signature_bytes);
+    Ok(axum::Json(TreeHead {
+        tree_size,
+        root_hash,
+        timestamp,
+        signature,
+    }))
+}
+
+#[tracing::instrument(skip_all)]
+async fn inclusion_proof(
+    State(state): State<AppContext>,
+    Query(query): Query<InclusionProofQuery>,
+) -> Result<impl IntoResponse> {
+    let transparency_log = state.transparency_log()?;
+
+    let proof = match (query.leaf_index, query.leaf_hash) {
+        (Some(leaf_index), None) => {
+            transparency_log
+                .inclusion_proof(leaf_index, query.tree_size)
+                .await?
+        }
+        (None, Some(leaf_hash)) => {
+            let leaf_hash: transparency::Hash = STANDARD
+                .decode(&leaf_hash)
+                .ok()
+                .and_then(|leaf_hash| leaf_hash.try_into().ok())
+                .ok_or_else(|| {
+                    AppError::InvalidLogQuery(format!("'{leaf_hash}' isn't a base64 SHA-256 hash"))
+                })?;
+            transparency_log
+                .inclusion_proof_by_hash(&leaf_hash, query.tree_size)
+                .await?
+        }
+        _ => {
+            return Err(AppError::InvalidLogQuery(String::from(
+                "exactly one of leafIndex and leafHash is required",
+            ))
+            .into())
+        }
+    };
+
+    Ok(axum::Json(proof))
+}
+
+#[tracing::instrument(skip_all)]
+async fn consistency_proof(
+    State(state): State<AppContext>,
+    Query(query): Query<ConsistencyProofQuery>,
+) -> Result<impl IntoResponse> {
+    let proof = state
+        .transparency_log()?
+        .consistency_proof(query.first, query.second)
+        .await?;
 
-    Ok(format!("{key_name}:{signature_base64}"))
+    Ok(axum::Json(proof))
 }
@@ -0,0 +1,256 @@
+//! Prometheus metrics, served in the OpenMetrics text format from `/metrics`.
+
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use axum::extract::MatchedPath;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+
+use crate::error::{AppError, Result};
+use crate::nix::{PathInfo, PathInfoSource};
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SignatureLabels {
+    key: String,
+    route: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ErrorLabels {
+    error: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct BackendLabels {
+    backend: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    route: String,
+    method: String,
+    status: u16,
+}
+
+type HistogramFamily<L> = Family<L, Histogram, fn() -> Histogram>;
+
+pub struct Metrics {
+    registry: Registry,
+    signatures: Family<SignatureLabels, Counter>,
+    errors: Family<ErrorLabels, Counter>,
+    path_info_duration: HistogramFamily<BackendLabels>,
+    path_info_failures: Family<BackendLabels, Counter>,
+    request_duration: HistogramFamily<RequestLabels>,
+    requests_in_flight: Gauge,
+}
+
+/// The metrics for the whole process, since errors and path info lookups happen far from the
+/// app state.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+fn duration_histogram() -> Histogram {
+    // 1ms up to about 16s
+    Histogram::new(exponential_buckets(0.001, 2.0, 15))
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let mut registry = Registry::with_prefix("cache_signing_server");
+
+        let signatures = Family::default();
+        registry.register(
+            "signatures",
+            "Signatures made, by key and route",
+            signatures.clone(),
+        );
+        let errors = Family::default();
+        registry.register("errors", "Error responses, by error", errors.clone());
+        let path_info_duration: HistogramFamily<_> =
+            Family::new_with_constructor(duration_histogram);
+        registry.register(
+            "path_info_duration_seconds",
+            "How long path info lookups took, by backend",
+            path_info_duration.clone(),
+        );
+        let path_info_failures = Family::default();
+        registry.register(
+            "path_info_failures",
+            "Path info lookups that failed, by backend",
+            path_info_failures.clone(),
+        );
+        let request_duration: HistogramFamily<_> = Family::new_with_constructor(duration_histogram);
+        registry.register(
+            "request_duration_seconds",
+            "How long requests took to respond to, by route, method and status",
+            request_duration.clone(),
+        );
+        let requests_in_flight = Gauge::default();
+        registry.register(
+            "requests_in_flight",
+            "Requests being handled",
+            requests_in_flight.clone(),
+        );
+
+        Self {
+            registry,
+            signatures,
+            errors,
+            path_info_duration,
+            path_info_failures,
+            request_duration,
+            requests_in_flight,
+        }
+    }
+
+    /// Count signatures in `name:base64sig` form, one per line.
+    pub fn record_signatures(&self, route: &str, signatures: &str) {
+        for signature in signatures.lines() {
+            let key = signature.split_once(':').map_or("", |(key, _)| key);
+            self.signatures
+                .get_or_create(&SignatureLabels {
+                    key: key.to_string(),
+                    route: route.to_string(),
+                })
+                .inc();
+        }
+    }
+
+    pub fn record_error(&self, error: &str) {
+        self.errors
+            .get_or_create(&ErrorLabels {
+                error: error.to_string(),
+            })
+            .inc();
+    }
+
+    pub fn encode(&self) -> Result<String> {
+        let mut encoded = String::new();
+        prometheus_client::encoding::text::encode(&mut encoded, &self.registry)?;
+
+        Ok(encoded)
+    }
+}
+
+/// Time requests and count the ones in flight.
+pub async fn track<B>(
+    matched_path: Option<MatchedPath>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let metrics = metrics();
+    let route = matched_path.map_or_else(
+        || String::from("<unmatched>"),
+        |path| path.as_str().to_string(),
+    );
+    let method = request.method().to_string();
+
+    let in_flight = InFlight::start(&metrics.requests_in_flight);
+    let start = Instant::now();
+    let response = next.run(request).await;
+    drop(in_flight);
+
+    metrics
+        .request_duration
+        .get_or_create(&RequestLabels {
+            route,
+            method,
+            status: response.status().as_u16(),
+        })
+        .observe(start.elapsed().as_secs_f64());
+
+    response
+}
+
+/// Counts a request as in flight until it's dropped, which happens even if the client goes away
+/// before it's answered.
+struct InFlight<'a>(&'a Gauge);
+
+impl<'a> InFlight<'a> {
+    fn start(requests_in_flight: &'a Gauge) -> Self {
+        requests_in_flight.inc();
+        Self(requests_in_flight)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+pub async fn serve_metrics() -> Result<impl IntoResponse> {
+    Ok((
+        [(
+            CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        metrics().encode()?,
+    ))
+}
+
+/// Times the lookups of another path info source.
+pub struct MeasuredPathInfoSource {
+    backend: BackendLabels,
+    source: Box<dyn PathInfoSource>,
+}
+
+impl MeasuredPathInfoSource {
+    pub fn new(backend: String, source: Box<dyn PathInfoSource>) -> Self {
+        Self {
+            backend: BackendLabels { backend },
+            source,
+        }
+    }
+
+    fn observe<T>(&self, start: Instant, result: &Result<T>) {
+        let metrics = metrics();
+        metrics
+            .path_info_duration
+            .get_or_create(&self.backend)
+            .observe(start.elapsed().as_secs_f64());
+        // A store path that isn't there is the caller's problem, not the backend's
+        if let Err(err) = result {
+            if !matches!(err.app_error(), Some(AppError::StorePathNotValid(_))) {
+                metrics
+                    .path_info_failures
+                    .get_or_create(&self.backend)
+                    .inc();
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PathInfoSource for MeasuredPathInfoSource {
+    async fn query_path_info(&self, store_path: &str) -> Result<Option<PathInfo>> {
+        let start = Instant::now();
+        let result = self.source.query_path_info(store_path).await;
+        self.observe(start, &result);
+
+        result
+    }
+
+    async fn query_path_infos(
+        &self,
+        store_paths: &[String],
+        recursive: bool,
+    ) -> Result<Vec<PathInfo>> {
+        let start = Instant::now();
+        let result = self.source.query_path_infos(store_paths, recursive).await;
+        self.observe(start, &result);
+
+        result
+    }
+}
@@ -0,0 +1,105 @@
+use std::path::PathBuf;
+use std::process::Output;
+
+use tokio::process::Command;
+
+use super::{PathInfo, PathInfoSource};
+use crate::error::{AppError, Result};
+
+/// Runs `nix path-info --json`.
+#[derive(Debug, Default)]
+pub struct NixCommand;
+
+#[async_trait::async_trait]
+impl PathInfoSource for NixCommand {
+    async fn query_path_info(&self, store_path: &str) -> Result<Option<PathInfo>> {
+        match self
+            .query_path_infos(&[store_path.to_string()], false)
+            .await
+        {
+            Ok(path_infos) => Ok(path_infos.into_iter().next()),
+            Err(err) if matches!(err.app_error(), Some(AppError::StorePathNotValid(_))) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    #[tracing::instrument(skip(self, store_paths))]
+    async fn query_path_infos(
+        &self,
+        store_paths: &[String],
+        recursive: bool,
+    ) -> Result<Vec<PathInfo>> {
+        if let Some(store_path) = store_paths
+            .iter()
+            .find(|store_path| !PathBuf::from(store_path).exists())
+        {
+            return Err(AppError::StorePathNotValid(store_path.to_string()).into());
+        }
+
+        let mut command = Command::new("nix");
+        command
+            .args(["--extra-experimental-features", "nix-command"])
+            .arg("path-info")
+            .arg("--json");
+        if recursive {
+            command.arg("--recursive");
+        }
+        let nix_path_info_output = match command.args(store_paths).output().await {
+            Ok(output) => output,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(
+                    AppError::NixUnavailable(String::from("`nix` isn't on the PATH")).into(),
+                );
+            }
+            Err(err) => {
+                return Err(AppError::NixUnavailable(format!("failed to run `nix`: {err}")).into());
+            }
+        };
+
+        parse_output(store_paths, &nix_path_info_output)
+    }
+}
+
+/// What `nix` printed to stderr, as the source of an error so it's logged with it.
+#[derive(Debug, thiserror::Error)]
+#[error("Stderr:\n{0}")]
+struct NixStderr(String);
+
+/// Get the path info out of what `nix path-info --json` printed.
+pub fn parse_output(store_paths: &[String], output: &Output) -> Result<Vec<PathInfo>> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stderr = stderr.trim_end();
+    if !output.status.success() {
+        let err = match not_valid_store_path(stderr) {
+            Some(store_path) => AppError::StorePathNotValid(store_path.to_string()),
+            // Like `error: cannot connect to socket at '/nix/var/nix/daemon-socket/socket': ...`
+            None if stderr.contains("cannot connect to socket") => {
+                AppError::NixUnavailable(String::from("the Nix daemon can't be reached"))
+            }
+            None => AppError::NixCommandFailed(output.status.to_string()),
+        };
+        // Logged with the error, but not sent to the caller
+        return Err(color_eyre::Report::new(NixStderr(stderr.to_string()))
+            .wrap_err(err)
+            .into());
+    }
+    if !stderr.is_empty() {
+        tracing::debug!("nix path-info printed to stderr:\n{stderr}");
+    }
+
+    let nix_path_infos: Vec<PathInfo> = serde_json::from_slice(&output.stdout)
+        .map_err(|err| AppError::InvalidNixOutput(err.to_string()))?;
+    if nix_path_infos.is_empty() {
+        return Err(AppError::NoPathInfo(store_paths.join(", ")).into());
+    }
+
+    Ok(nix_path_infos)
+}
+
+/// The store path Nix complains isn't valid, from `error: path '<store path>' is not valid`.
+fn not_valid_store_path(stderr: &str) -> Option<&str> {
+    stderr.lines().find_map(|line| {
+        let (_, rest) = line.split_once("path '")?;
+        rest.strip_suffix("' is not valid")
+    })
+}
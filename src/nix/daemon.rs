@@ -0,0 +1,291 @@
+//! A minimal client for the Nix daemon worker protocol, enough to query path info.
+// Adapted from:
+// https://github.com/NixOS/nix/blob/ea2f74cbe178d31748d63037e238e3a4a8e02cf3/src/libstore/worker-protocol.hh
+// https://github.com/NixOS/nix/blob/ea2f74cbe178d31748d63037e238e3a4a8e02cf3/src/libstore/remote-store.cc
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use color_eyre::eyre::WrapErr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::UnixStream;
+use tokio::sync::Semaphore;
+
+use super::{PathInfo, PathInfoSource, SRIHash};
+use crate::error::Result;
+
+pub const DEFAULT_SOCKET: &str = "/nix/var/nix/daemon-socket/socket";
+
+const WORKER_MAGIC_1: u64 = 0x6e697863;
+const WORKER_MAGIC_2: u64 = 0x6478696f;
+
+/// The protocol version we speak, 1.21. Newer daemons talk down to it.
+const PROTOCOL_VERSION: u64 = (1 << 8) | 21;
+/// Path info replies only say whether the path is valid since 1.17.
+const MIN_DAEMON_MINOR: u64 = 17;
+
+const STDERR_NEXT: u64 = 0x6f6c6d67;
+const STDERR_LAST: u64 = 0x616c7473;
+const STDERR_ERROR: u64 = 0x63787470;
+const STDERR_START_ACTIVITY: u64 = 0x53545254;
+const STDERR_STOP_ACTIVITY: u64 = 0x53544f50;
+const STDERR_RESULT: u64 = 0x52534c54;
+
+const OP_QUERY_PATH_INFO: u64 = 26;
+
+/// Don't allocate unbounded buffers because of a bogus length from the socket.
+const MAX_STRING_LENGTH: u64 = 64 * 1024 * 1024;
+
+/// How many connections to the daemon may be open at once, each serving one lookup at a time.
+pub const MAX_CONNECTIONS: usize = 8;
+
+/// Asks the Nix daemon, reusing connections between queries.
+pub struct NixDaemon {
+    socket: PathBuf,
+    /// Connections that aren't in use, for the next queries to reuse
+    idle: Mutex<Vec<DaemonClient<UnixStream>>>,
+    /// One permit per connection that may be open
+    connections: Semaphore,
+}
+
+impl NixDaemon {
+    pub fn new(socket: PathBuf) -> Self {
+        Self {
+            socket,
+            idle: Default::default(),
+            connections: Semaphore::new(MAX_CONNECTIONS),
+        }
+    }
+
+    fn take_idle(&self) -> Option<DaemonClient<UnixStream>> {
+        self.idle.lock().ok()?.pop()
+    }
+}
+
+#[async_trait::async_trait]
+impl PathInfoSource for NixDaemon {
+    async fn query_path_info(&self, store_path: &str) -> Result<Option<PathInfo>> {
+        // Wait for a connection to free up, rather than opening as many as there are lookups
+        let _permit = self.connections.acquire().await?;
+        let mut client = match self.take_idle() {
+            Some(client) => client,
+            None => DaemonClient::connect(&self.socket).await?,
+        };
+
+        let result = client.query_path_info(store_path).await;
+        // We don't know how much of the reply was left unread after an error, so it's dropped
+        if result.is_ok() {
+            if let Ok(mut idle) = self.idle.lock() {
+                idle.push(client);
+            }
+        }
+
+        result
+    }
+}
+
+pub struct DaemonClient<S> {
+    stream: BufStream<S>,
+}
+
+impl DaemonClient<UnixStream> {
+    #[tracing::instrument]
+    pub async fn connect(socket: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(socket).await.wrap_err_with(|| {
+            format!(
+                "Failed to connect to the Nix daemon at {}",
+                socket.display()
+            )
+        })?;
+
+        Self::handshake(stream).await
+    }
+}
+
+impl<S> DaemonClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub async fn handshake(stream: S) -> Result<Self> {
+        let mut client = Self {
+            stream: BufStream::new(stream),
+        };
+
+        client.write_u64(WORKER_MAGIC_1).await?;
+        client.stream.flush().await?;
+
+        let magic = client.read_u64().await?;
+        if magic != WORKER_MAGIC_2 {
+            return Err(color_eyre::eyre::eyre!("Nix daemon sent bad magic {magic:#x}").into());
+        }
+
+        let daemon_version = client.read_u64().await?;
+        let (major, minor) = (daemon_version >> 8, daemon_version & 0xff);
+        if major != 1 || minor < MIN_DAEMON_MINOR {
+            return Err(color_eyre::eyre::eyre!(
+                "Nix daemon protocol version {major}.{minor} is not supported"
+            )
+            .into());
+        }
+        tracing::trace!("Nix daemon speaks protocol version {major}.{minor}");
+
+        client.write_u64(PROTOCOL_VERSION).await?;
+        // CPU affinity (since 1.14) and reserve space (since 1.11), neither of which we want
+        client.write_u64(0).await?;
+        client.write_u64(0).await?;
+        client.stream.flush().await?;
+
+        client.process_stderr().await?;
+
+        Ok(client)
+    }
+
+    /// Query the path info of a store path, returning `None` if it isn't valid.
+    #[tracing::instrument(skip(self))]
+    pub async fn query_path_info(&mut self, store_path: &str) -> Result<Option<PathInfo>> {
+        self.write_u64(OP_QUERY_PATH_INFO).await?;
+        self.write_string(store_path).await?;
+        self.stream.flush().await?;
+
+        self.process_stderr().await?;
+
+        if !self.read_bool().await? {
+            return Ok(None);
+        }
+
+        let deriver = self.read_string().await?;
+        let nar_hash = self.read_string().await?;
+        let references = self.read_strings().await?;
+        let _registration_time = self.read_u64().await?;
+        let nar_size = self.read_u64().await?;
+        let _ultimate = self.read_bool().await?;
+        let signatures = self.read_strings().await?;
+        let ca = self.read_string().await?;
+
+        Ok(Some(PathInfo {
+            // The daemon sends the NAR hash as base16 without its type
+            nar_hash: SRIHash::from_nix_hash(&format!("sha256:{nar_hash}"))?,
+            nar_size,
+            store_path: store_path.to_string(),
+            references,
+            deriver: Some(deriver).filter(|deriver| !deriver.is_empty()),
+            signatures,
+            ca: Some(ca).filter(|ca| !ca.is_empty()),
+        }))
+    }
+
+    /// Drain log messages the daemon sends while working, until it's done.
+    async fn process_stderr(&mut self) -> Result<()> {
+        loop {
+            match self.read_u64().await? {
+                STDERR_LAST => return Ok(()),
+                STDERR_NEXT => {
+                    let message = self.read_string().await?;
+                    tracing::debug!("nix-daemon: {}", message.trim_end());
+                }
+                STDERR_START_ACTIVITY => {
+                    let _id = self.read_u64().await?;
+                    let _level = self.read_u64().await?;
+                    let _type = self.read_u64().await?;
+                    let text = self.read_string().await?;
+                    self.read_fields().await?;
+                    let _parent = self.read_u64().await?;
+                    if !text.is_empty() {
+                        tracing::trace!("nix-daemon: {text}");
+                    }
+                }
+                STDERR_STOP_ACTIVITY => {
+                    let _id = self.read_u64().await?;
+                }
+                STDERR_RESULT => {
+                    let _id = self.read_u64().await?;
+                    let _type = self.read_u64().await?;
+                    self.read_fields().await?;
+                }
+                STDERR_ERROR => {
+                    // Structured errors are only sent to clients speaking 1.26 or newer
+                    let message = self.read_string().await?;
+                    let _status = self.read_u64().await?;
+                    return Err(color_eyre::eyre::eyre!("Nix daemon error: {message}").into());
+                }
+                other => {
+                    return Err(color_eyre::eyre::eyre!(
+                        "Nix daemon sent unknown message {other:#x}"
+                    )
+                    .into())
+                }
+            }
+        }
+    }
+
+    async fn read_fields(&mut self) -> Result<()> {
+        let count = self.read_u64().await?;
+        for _ in 0..count {
+            match self.read_u64().await? {
+                0 => {
+                    self.read_u64().await?;
+                }
+                1 => {
+                    self.read_string().await?;
+                }
+                other => {
+                    return Err(color_eyre::eyre::eyre!(
+                        "Nix daemon sent unknown field type {other}"
+                    )
+                    .into())
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn read_u64(&mut self) -> Result<u64> {
+        Ok(self.stream.read_u64_le().await?)
+    }
+
+    async fn read_bool(&mut self) -> Result<bool> {
+        Ok(self.read_u64().await? != 0)
+    }
+
+    async fn read_string(&mut self) -> Result<String> {
+        let len = self.read_u64().await?;
+        if len > MAX_STRING_LENGTH {
+            return Err(color_eyre::eyre::eyre!("Nix daemon sent a {len} byte string").into());
+        }
+
+        let mut buf = vec![0u8; padded_len(len as usize)];
+        self.stream.read_exact(&mut buf).await?;
+        buf.truncate(len as usize);
+
+        Ok(String::from_utf8(buf)?)
+    }
+
+    async fn read_strings(&mut self) -> Result<Vec<String>> {
+        let count = self.read_u64().await?;
+        let mut strings = Vec::new();
+        for _ in 0..count {
+            strings.push(self.read_string().await?);
+        }
+
+        Ok(strings)
+    }
+
+    async fn write_u64(&mut self, value: u64) -> Result<()> {
+        Ok(self.stream.write_u64_le(value).await?)
+    }
+
+    async fn write_string(&mut self, value: &str) -> Result<()> {
+        self.write_u64(value.len() as u64).await?;
+        self.stream.write_all(value.as_bytes()).await?;
+        let padding = padded_len(value.len()) - value.len();
+        self.stream.write_all(&[0u8; 8][..padding]).await?;
+
+        Ok(())
+    }
+}
+
+/// Strings are padded with zeroes to a multiple of 8 bytes.
+fn padded_len(len: usize) -> usize {
+    len.div_ceil(8) * 8
+}
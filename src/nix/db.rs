@@ -0,0 +1,120 @@
+//! Read path info straight out of the Nix store database.
+// Adapted from:
+// https://github.com/NixOS/nix/blob/ea2f74cbe178d31748d63037e238e3a4a8e02cf3/src/libstore/local-store.cc
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use color_eyre::eyre::WrapErr;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+
+use super::{PathInfo, PathInfoSource, SRIHash};
+use crate::error::Result;
+
+pub const DEFAULT_DB: &str = "/nix/var/nix/db/db.sqlite";
+
+/// Reads the Nix store database, opening it on first use.
+#[derive(Debug)]
+pub struct NixDb {
+    db: PathBuf,
+    connection: Arc<Mutex<Option<DbConnection>>>,
+}
+
+impl NixDb {
+    pub fn new(db: PathBuf) -> Self {
+        Self {
+            db,
+            connection: Default::default(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PathInfoSource for NixDb {
+    async fn query_path_info(&self, store_path: &str) -> Result<Option<PathInfo>> {
+        let db = self.db.clone();
+        let connection = self.connection.clone();
+        let store_path = store_path.to_string();
+
+        // SQLite is blocking, so keep it off the runtime
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| color_eyre::eyre::eyre!("Nix database connection was poisoned"))?;
+            let connection = match connection.as_mut() {
+                Some(connection) => connection,
+                None => connection.insert(DbConnection::open(&db)?),
+            };
+
+            connection.query_path_info(&store_path)
+        })
+        .await?
+    }
+}
+
+#[derive(Debug)]
+struct DbConnection {
+    connection: Connection,
+}
+
+impl DbConnection {
+    /// Open the database read-only, so we can never corrupt the store.
+    #[tracing::instrument]
+    pub fn open(db: &Path) -> Result<Self> {
+        let connection = Connection::open_with_flags(
+            db,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .wrap_err_with(|| format!("Failed to open the Nix database at {}", db.display()))?;
+
+        Ok(Self { connection })
+    }
+
+    /// Query the path info of a store path, returning `None` if it isn't valid.
+    #[tracing::instrument(skip(self))]
+    pub fn query_path_info(&self, store_path: &str) -> Result<Option<PathInfo>> {
+        let row = self
+            .connection
+            .prepare_cached(
+                "select id, hash, deriver, narSize, sigs, ca from ValidPaths where path = ?",
+            )?
+            .query_row([store_path], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<i64>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                ))
+            })
+            .optional()?;
+        let Some((id, hash, deriver, nar_size, sigs, ca)) = row else {
+            return Ok(None);
+        };
+
+        let references = self
+            .connection
+            .prepare_cached(
+                // Fingerprints list references in order, like Nix's `StorePathSet`
+                "select path from Refs join ValidPaths on reference = id where referrer = ? order by path",
+            )?
+            .query_map([id], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Some(PathInfo {
+            // Hashes are stored as `<type>:<base16>`
+            nar_hash: SRIHash::from_nix_hash(&hash)?,
+            nar_size: nar_size.unwrap_or_default().try_into()?,
+            store_path: store_path.to_string(),
+            references,
+            deriver: deriver.filter(|deriver| !deriver.is_empty()),
+            signatures: sigs
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(String::from)
+                .collect(),
+            ca: ca.filter(|ca| !ca.is_empty()),
+        }))
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::BTreeSet;
+
+use crate::error::{AppError, Result};
+
+use super::{NixHashType, PathInfo, SRIHash};
+
+/// The longest store path name Nix allows.
+const MAX_NAME_LENGTH: usize = 211;
+/// The length of the hash part of a store path, in Nix base32.
+const HASH_PART_LENGTH: usize = 32;
+
+/// What a narinfo signature is made over:
+/// `1;<store path>;<NAR hash>;<NAR size>;<references>`.
+// Adapted from:
+// https://github.com/NixOS/nix/blob/ea2f74cbe178d31748d63037e238e3a4a8e02cf3/src/libstore/path-info.cc#L8-L18
+// https://github.com/NixOS/nix/blob/ea2f74cbe178d31748d63037e238e3a4a8e02cf3/src/libstore/path.cc#L7-L28
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub store_path: String,
+    pub nar_hash: SRIHash,
+    pub nar_size: u64,
+    pub references: Vec<String>,
+}
+
+impl Fingerprint {
+    /// Parse a fingerprint, which must be exactly what `PathInfo::fingerprint` would produce.
+    #[tracing::instrument]
+    pub fn parse(fingerprint: &str) -> Result<Self> {
+        let malformed = |reason: String| AppError::MalformedFingerprint(reason);
+
+        let fields: Vec<&str> = fingerprint.split(';').collect();
+        let [version, store_path, nar_hash, nar_size, references] = fields[..] else {
+            return Err(malformed(String::from(
+                "expected `1;<store path>;<NAR hash>;<NAR size>;<references>`",
+            ))
+            .into());
+        };
+
+        if version != "1" {
+            return Err(malformed(format!("unsupported version '{version}'")).into());
+        }
+
+        let store_dir = check_store_path(store_path)
+            .map_err(|reason| malformed(format!("store path '{store_path}' {reason}")))?;
+
+        // Fingerprints always have base32 hashes, with their type
+        let (hash_type, digest) = nar_hash
+            .split_once(':')
+            .ok_or_else(|| malformed(format!("NAR hash '{nar_hash}' has no type")))?;
+        let hash_type: NixHashType = hash_type
+            .parse()
+            .map_err(|_| malformed(format!("unsupported hash type '{hash_type}'")))?;
+        if digest.len() != (hash_type.hash_size() * 8 - 1) / 5 + 1 {
+            return Err(malformed(format!(
+                "NAR hash '{nar_hash}' isn't a base32 {hash_type} hash"
+            ))
+            .into());
+        }
+        let nar_hash = SRIHash::from_nix_hash(nar_hash)
+            .map_err(|_| malformed(format!("invalid NAR hash '{nar_hash}'")))?;
+
+        let nar_size = nar_size
+            .parse()
+            .map_err(|e| malformed(format!("invalid NAR size '{nar_size}': {e}")))?;
+
+        let references = match references {
+            "" => Vec::new(),
+            references => references
+                .split(',')
+                .map(|reference| {
+                    match check_store_path(reference) {
+                        Ok(dir) if dir == store_dir => Ok(reference.to_string()),
+                        Ok(dir) => Err(format!("is in {dir} rather than {store_dir}")),
+                        Err(reason) => Err(reason),
+                    }
+                    .map_err(|reason| malformed(format!("reference '{reference}' {reason}")))
+                })
+                .collect::<Result<_, _>>()?,
+        };
+
+        let parsed = Self {
+            store_path: store_path.to_string(),
+            nar_hash,
+            nar_size,
+            references,
+        };
+
+        // Anything that survived but would be printed differently, like a NAR size of `+1`
+        if PathInfo::from(&parsed).fingerprint()? != fingerprint {
+            return Err(malformed(String::from("not in canonical form")).into());
+        }
+
+        Ok(parsed)
+    }
+
+    /// How this differs from the path info of the same store path, if at all.
+    pub fn mismatch(&self, path_info: &PathInfo) -> Option<String> {
+        if self.nar_hash != path_info.nar_hash {
+            return Some(format!(
+                "NAR hash is {} rather than {}",
+                self.nar_hash.0, path_info.nar_hash.0
+            ));
+        }
+        if self.nar_size != path_info.nar_size {
+            return Some(format!(
+                "NAR size is {} rather than {}",
+                self.nar_size, path_info.nar_size
+            ));
+        }
+
+        // The order of references doesn't matter to Nix
+        let references: BTreeSet<&String> = self.references.iter().collect();
+        let expected: BTreeSet<&String> = path_info.references.iter().collect();
+        if let Some(extra) = references.difference(&expected).next() {
+            return Some(format!("'{extra}' isn't a reference"));
+        }
+        if let Some(missing) = expected.difference(&references).next() {
+            return Some(format!("reference '{missing}' is missing"));
+        }
+
+        None
+    }
+}
+
+impl std::str::FromStr for Fingerprint {
+    type Err = crate::error::Report;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl From<&Fingerprint> for PathInfo {
+    fn from(fingerprint: &Fingerprint) -> Self {
+        PathInfo {
+            nar_hash: fingerprint.nar_hash.clone(),
+            nar_size: fingerprint.nar_size,
+            store_path: fingerprint.store_path.clone(),
+            references: fingerprint.references.clone(),
+            deriver: None,
+            signatures: Vec::new(),
+            ca: None,
+        }
+    }
+}
+
+/// Check a store path is `<store dir>/<hash>-<name>`, returning the store directory.
+fn check_store_path(store_path: &str) -> Result<&str, String> {
+    let Some((store_dir, basename)) = store_path.rsplit_once('/') else {
+        return Err(String::from("is not absolute"));
+    };
+    if !store_dir.starts_with('/') {
+        return Err(String::from("is not absolute"));
+    }
+
+    let hash_part = basename.get(..HASH_PART_LENGTH).unwrap_or_default();
+    if hash_part.len() != HASH_PART_LENGTH
+        || !hash_part
+            .bytes()
+            .all(|c| SRIHash::BASE32_CHARS.contains(&c))
+    {
+        return Err(String::from("doesn't start with a base32 hash"));
+    }
+
+    let Some(name) = basename[HASH_PART_LENGTH..].strip_prefix('-') else {
+        return Err(String::from("has no name"));
+    };
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(format!(
+            "has a name not between 1 and {MAX_NAME_LENGTH} characters"
+        ));
+    }
+    if name.starts_with('.') {
+        return Err(String::from("has a name starting with '.'"));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|&c| !(c.is_ascii_alphanumeric() || "+-._?=".contains(c)))
+    {
+        return Err(format!("has a name containing '{c}'"));
+    }
+
+    Ok(store_dir)
+}
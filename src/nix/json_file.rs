@@ -0,0 +1,43 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use color_eyre::eyre::WrapErr;
+
+use super::{PathInfo, PathInfoSource};
+use crate::error::Result;
+
+/// A fixed set of path infos read from a file, for tests and for signing without a Nix store.
+#[derive(Debug, Default)]
+pub struct JsonFile {
+    path_infos: BTreeMap<String, PathInfo>,
+}
+
+impl JsonFile {
+    /// Load path infos from a file holding the output of `nix path-info --json`.
+    #[tracing::instrument]
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents =
+            std::fs::read(path).wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        let path_infos: Vec<PathInfo> = serde_json::from_slice(&contents)
+            .wrap_err_with(|| format!("Failed to parse path info from {}", path.display()))?;
+
+        Ok(Self::from_path_infos(path_infos))
+    }
+
+    /// Hold path infos already in memory.
+    pub fn from_path_infos(path_infos: Vec<PathInfo>) -> Self {
+        Self {
+            path_infos: path_infos
+                .into_iter()
+                .map(|path_info| (path_info.store_path.clone(), path_info))
+                .collect(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PathInfoSource for JsonFile {
+    async fn query_path_info(&self, store_path: &str) -> Result<Option<PathInfo>> {
+        Ok(self.path_infos.get(store_path).cloned())
+    }
+}
@@ -0,0 +1,345 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use serde::Deserialize as _;
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::error::{AppError, Result};
+
+pub mod command;
+pub mod daemon;
+pub mod db;
+mod fingerprint;
+mod json_file;
+mod narinfo;
+
+pub use command::NixCommand;
+pub use daemon::NixDaemon;
+pub use db::NixDb;
+pub use fingerprint::Fingerprint;
+pub use json_file::JsonFile;
+pub use narinfo::Narinfo;
+
+/// Somewhere to look up the path info of store paths.
+#[async_trait::async_trait]
+pub trait PathInfoSource: Send + Sync {
+    /// Get the path info of a store path, or `None` if it isn't valid.
+    async fn query_path_info(&self, store_path: &str) -> Result<Option<PathInfo>>;
+
+    /// Get the path info of every store path, and of their closures if `recursive` is set.
+    async fn query_path_infos(
+        &self,
+        store_paths: &[String],
+        recursive: bool,
+    ) -> Result<Vec<PathInfo>> {
+        if !recursive {
+            let mut path_infos = Vec::with_capacity(store_paths.len());
+            for store_path in store_paths {
+                path_infos.push(self.require_path_info(store_path).await?);
+            }
+
+            return Ok(path_infos);
+        }
+
+        let mut closure = BTreeMap::new();
+        let mut queue: VecDeque<String> = store_paths.iter().cloned().collect();
+
+        while let Some(store_path) = queue.pop_front() {
+            if closure.contains_key(&store_path) {
+                continue;
+            }
+
+            let path_info = self.require_path_info(&store_path).await?;
+            queue.extend(path_info.references.iter().cloned());
+            closure.insert(store_path, path_info);
+        }
+
+        Ok(closure.into_values().collect())
+    }
+
+    async fn require_path_info(&self, store_path: &str) -> Result<PathInfo> {
+        self.query_path_info(store_path)
+            .await?
+            .ok_or_else(|| AppError::StorePathNotValid(store_path.to_string()).into())
+    }
+}
+
+#[derive(Debug, Clone, serde_derive::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PathInfo {
+    pub nar_hash: SRIHash,
+    pub nar_size: u64,
+    #[serde(rename = "path")]
+    pub store_path: String,
+    pub references: Vec<String>,
+    #[serde(default)]
+    pub deriver: Option<String>,
+    // Only read by `Narinfo::from_path_info`, which only tests use so far
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub signatures: Vec<String>,
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub ca: Option<String>,
+}
+
+/// The name of a store path after its hash, like `hello-2.12.1` for
+/// `/nix/store/<hash>-hello-2.12.1`.
+pub fn store_path_name(store_path: &str) -> Option<&str> {
+    let (_, basename) = store_path.rsplit_once('/')?;
+    let (_, name) = basename.split_once('-')?;
+    Some(name)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde_derive::Deserialize)]
+pub struct SRIHash(#[serde(deserialize_with = "deserialize_sri_hash")] pub ssri::Hash);
+
+fn deserialize_sri_hash<'de, D>(deserializer: D) -> Result<ssri::Hash, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    String::deserialize(deserializer)
+        .and_then(|hash| hash.parse::<ssri::Hash>().map_err(serde::de::Error::custom))
+}
+
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum NixHashType {
+    // NOTE: ssri::Algorithm doesn't support md5, which means we can't either
+    // (unless we want to reimplement SRI parsing)
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl std::fmt::Display for NixHashType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let algo_str = match self {
+            NixHashType::Sha1 => "sha1",
+            NixHashType::Sha256 => "sha256",
+            NixHashType::Sha512 => "sha512",
+        };
+
+        f.write_str(algo_str)
+    }
+}
+
+impl NixHashType {
+    /// The length of the digest in bytes.
+    pub fn hash_size(self) -> usize {
+        match self {
+            NixHashType::Sha1 => 20,
+            NixHashType::Sha256 => 32,
+            NixHashType::Sha512 => 64,
+        }
+    }
+}
+
+impl std::str::FromStr for NixHashType {
+    type Err = crate::error::Report;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "sha1" => Ok(NixHashType::Sha1),
+            "sha256" => Ok(NixHashType::Sha256),
+            "sha512" => Ok(NixHashType::Sha512),
+            algo => Err(AppError::UnsupportedHashAlgorithm(algo.to_string()).into()),
+        }
+    }
+}
+
+impl From<NixHashType> for ssri::Algorithm {
+    fn from(value: NixHashType) -> Self {
+        match value {
+            NixHashType::Sha1 => ssri::Algorithm::Sha1,
+            NixHashType::Sha256 => ssri::Algorithm::Sha256,
+            NixHashType::Sha512 => ssri::Algorithm::Sha512,
+        }
+    }
+}
+
+impl TryFrom<ssri::Algorithm> for NixHashType {
+    type Error = crate::error::Report;
+
+    fn try_from(value: ssri::Algorithm) -> std::result::Result<Self, Self::Error> {
+        match value {
+            ssri::Algorithm::Sha1 => Ok(NixHashType::Sha1),
+            ssri::Algorithm::Sha256 => Ok(NixHashType::Sha256),
+            ssri::Algorithm::Sha512 => Ok(NixHashType::Sha512),
+            algo => Err(AppError::UnsupportedHashAlgorithm(algo.to_string()).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NixBase32 {
+    pub hash_type: NixHashType,
+    pub digest: String,
+}
+
+impl std::fmt::Display for NixBase32 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { hash_type, digest } = self;
+        write!(f, "{hash_type}:{digest}")
+    }
+}
+
+impl SRIHash {
+    // https://github.com/NixOS/nix/blob/78e886bc5fd9e4d85f8503799540c0b71bb270be/src/libutil/hash.cc#L85
+    // ommitted: E O U T
+    pub const BASE32_CHARS: &'static [u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+    // Adapted from:
+    // https://github.com/NixOS/nix/blob/78e886bc5fd9e4d85f8503799540c0b71bb270be/src/libutil/hash.cc#L88-L108
+    #[tracing::instrument(skip_all)]
+    pub fn to_nix_base32(&self) -> Result<NixBase32> {
+        let base64_digest = &self.0.digest;
+        let digest_bytes = STANDARD.decode(base64_digest)?;
+        let digest_len = digest_bytes.len();
+        if digest_len == 0 {
+            return Err(color_eyre::eyre::eyre!("Hash '{}' has an empty digest", self.0).into());
+        }
+        let digest_base32_len = (digest_len * 8 - 1) / 5 + 1;
+        let mut base32_digest = String::with_capacity(digest_base32_len);
+
+        for n in (0..digest_base32_len).rev() {
+            let b: usize = n * 5;
+            let i: usize = b / 8;
+            let j: usize = b % 8;
+
+            let x = digest_bytes[i].checked_shr(j as u32).unwrap_or(0);
+            let y = if i >= digest_len - 1 {
+                0
+            } else {
+                digest_bytes[i + 1].checked_shl(8 - j as u32).unwrap_or(0)
+            };
+
+            let c = (x | y) as usize;
+            let ch = Self::BASE32_CHARS[c % Self::BASE32_CHARS.len()];
+            base32_digest.push(char::from(ch));
+        }
+
+        Ok(NixBase32 {
+            hash_type: self.0.algorithm.try_into()?,
+            digest: base32_digest,
+        })
+    }
+}
+
+impl SRIHash {
+    /// Parse a hash as Nix prints it: `<type>:<digest>` with a Nix base32 or
+    /// base16 digest, or an SRI hash.
+    #[tracing::instrument]
+    pub fn from_nix_hash(hash: &str) -> Result<Self> {
+        let wrong_length = |hash_type: NixHashType| {
+            color_eyre::eyre::eyre!("Hash '{hash}' has the wrong length for a {hash_type} hash")
+        };
+
+        let Some((hash_type, digest)) = hash.split_once(':') else {
+            let sri_hash: ssri::Hash = hash.parse()?;
+            // ssri takes digests of any length, even none at all
+            let hash_type = NixHashType::try_from(sri_hash.algorithm)?;
+            let digest_bytes = STANDARD
+                .decode(&sri_hash.digest)
+                .map_err(|_| color_eyre::eyre::eyre!("Invalid base64 digest in hash '{hash}'"))?;
+            if digest_bytes.len() != hash_type.hash_size() {
+                return Err(wrong_length(hash_type).into());
+            }
+
+            return Ok(Self(sri_hash));
+        };
+
+        let hash_type: NixHashType = hash_type.parse()?;
+        let hash_size = hash_type.hash_size();
+        let digest_bytes = if digest.len() == hash_size * 2 {
+            Self::from_base16(digest)?
+        } else if digest.len() == (hash_size * 8 - 1) / 5 + 1 {
+            Self::from_nix_base32(digest, hash_size)?
+        } else {
+            return Err(wrong_length(hash_type).into());
+        };
+
+        Ok(Self(ssri::Hash {
+            algorithm: hash_type.into(),
+            digest: STANDARD.encode(digest_bytes),
+        }))
+    }
+
+    fn from_base16(digest: &str) -> Result<Vec<u8>> {
+        (0..digest.len())
+            .step_by(2)
+            .map(|i| {
+                digest
+                    .get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .ok_or_else(|| {
+                        color_eyre::eyre::eyre!("Invalid base16 digest '{digest}'").into()
+                    })
+            })
+            .collect()
+    }
+
+    // Adapted from:
+    // https://github.com/NixOS/nix/blob/78e886bc5fd9e4d85f8503799540c0b71bb270be/src/libutil/hash.cc#L230-L252
+    fn from_nix_base32(digest: &str, hash_size: usize) -> Result<Vec<u8>> {
+        let mut digest_bytes = vec![0u8; hash_size];
+
+        for (n, ch) in digest.bytes().rev().enumerate() {
+            let c = Self::BASE32_CHARS
+                .iter()
+                .position(|&base32_char| base32_char == ch)
+                .ok_or_else(|| color_eyre::eyre::eyre!("Invalid base32 digest '{digest}'"))?
+                as u8;
+            let b = n * 5;
+            let i = b / 8;
+            let j = b % 8;
+
+            digest_bytes[i] |= c.checked_shl(j as u32).unwrap_or(0);
+            let carry = c.checked_shr(8 - j as u32).unwrap_or(0);
+            if i < hash_size - 1 {
+                digest_bytes[i + 1] |= carry;
+            } else if carry != 0 {
+                return Err(color_eyre::eyre::eyre!("Invalid base32 digest '{digest}'").into());
+            }
+        }
+
+        Ok(digest_bytes)
+    }
+}
+
+impl PathInfo {
+    /// The derivation output this path is, going by its name and its deriver's.
+    ///
+    /// Outputs other than `out` have their name appended to the derivation's,
+    /// like `hello-2.12.1-man`.
+    pub fn output_name(&self) -> Option<&str> {
+        let deriver_name = store_path_name(self.deriver.as_deref()?)?.strip_suffix(".drv")?;
+        match store_path_name(&self.store_path)?.strip_prefix(deriver_name)? {
+            "" => Some("out"),
+            output => output.strip_prefix('-'),
+        }
+    }
+
+    // Adapted from:
+    // https://github.com/NixOS/nix/blob/ea2f74cbe178d31748d63037e238e3a4a8e02cf3/src/libstore/path-info.cc#L8-L18
+    #[tracing::instrument(skip_all)]
+    pub fn fingerprint(&self) -> Result<String> {
+        let nar_size_string = self.nar_size.to_string();
+        let references_string = self.references.join(",");
+        let base32_nar_hash = self.nar_hash.to_nix_base32()?;
+        let base32_nar_hash_string = base32_nar_hash.to_string();
+
+        let fingerprint = [
+            "1;",
+            self.store_path.as_ref(),
+            ";",
+            base32_nar_hash_string.as_ref(),
+            ";",
+            nar_size_string.as_ref(),
+            ";",
+            references_string.as_ref(),
+        ];
+
+        Ok(fingerprint.into_iter().collect())
+    }
+}
@@ -0,0 +1,229 @@
+use crate::error::{AppError, Result};
+
+use super::{PathInfo, SRIHash};
+
+/// A `.narinfo` file, as served by binary caches.
+///
+/// References and the deriver are stored as basenames, like they are in the
+/// file itself.
+// Adapted from:
+// https://github.com/NixOS/nix/blob/ea2f74cbe178d31748d63037e238e3a4a8e02cf3/src/libstore/nar-info.cc
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Narinfo {
+    pub store_path: String,
+    pub url: String,
+    pub compression: Option<String>,
+    pub file_hash: Option<SRIHash>,
+    pub file_size: Option<u64>,
+    pub nar_hash: SRIHash,
+    pub nar_size: u64,
+    pub references: Vec<String>,
+    pub deriver: Option<String>,
+    pub sigs: Vec<String>,
+    pub ca: Option<String>,
+}
+
+impl Narinfo {
+    /// Build a narinfo for a path info whose NAR is served from `url`.
+    #[cfg(test)]
+    pub fn from_path_info(path_info: &PathInfo, url: impl Into<String>) -> Result<Self> {
+        let basename = |path: &str| -> Result<String> {
+            let (_, basename) = split_store_path(path)?;
+            Ok(basename.to_string())
+        };
+
+        Ok(Self {
+            store_path: path_info.store_path.clone(),
+            url: url.into(),
+            compression: None,
+            file_hash: None,
+            file_size: None,
+            nar_hash: path_info.nar_hash.clone(),
+            nar_size: path_info.nar_size,
+            references: path_info
+                .references
+                .iter()
+                .map(|reference| basename(reference))
+                .collect::<Result<_>>()?,
+            deriver: path_info.deriver.as_deref().map(basename).transpose()?,
+            sigs: path_info.signatures.clone(),
+            ca: path_info.ca.clone(),
+        })
+    }
+
+    /// The store directory `store_path` is in.
+    pub fn store_dir(&self) -> &str {
+        split_store_path(&self.store_path)
+            .map(|(store_dir, _)| store_dir)
+            .unwrap_or_default()
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn parse(narinfo: &str) -> Result<Self> {
+        let mut store_path = None;
+        let mut url = None;
+        let mut compression = None;
+        let mut file_hash = None;
+        let mut file_size = None;
+        let mut nar_hash = None;
+        let mut nar_size = None;
+        let mut references = None;
+        let mut deriver = None;
+        let mut sigs = Vec::new();
+        let mut ca = None;
+
+        for (line_number, line) in narinfo.lines().enumerate() {
+            let line_number = line_number + 1;
+            let malformed = |reason: String| {
+                AppError::MalformedNarinfo(format!("line {line_number}: {reason}"))
+            };
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let (field, value) = line
+                .split_once(": ")
+                .ok_or_else(|| malformed(format!("expected `<field>: <value>`, got '{line}'")))?;
+
+            // Nix only ever writes full-length sha256 hashes in narinfos
+            let parse_hash = |value: &str| match SRIHash::from_nix_hash(value) {
+                Ok(hash) if hash.0.algorithm == ssri::Algorithm::Sha256 => Ok(hash),
+                _ => Err(malformed(format!("invalid hash '{value}'"))),
+            };
+            let parse_size = |value: &str| {
+                value
+                    .parse::<u64>()
+                    .map_err(|e| malformed(format!("invalid size '{value}': {e}")))
+            };
+
+            let result = match field {
+                "StorePath" => {
+                    split_store_path(value)
+                        .map_err(|_| malformed(format!("invalid store path '{value}'")))?;
+                    set(&mut store_path, value.to_string())
+                }
+                "URL" => set(&mut url, value.to_string()),
+                "Compression" => set(&mut compression, value.to_string()),
+                "FileHash" => set(&mut file_hash, parse_hash(value)?),
+                "FileSize" => set(&mut file_size, parse_size(value)?),
+                "NarHash" => set(&mut nar_hash, parse_hash(value)?),
+                "NarSize" => set(&mut nar_size, parse_size(value)?),
+                "References" => set(
+                    &mut references,
+                    value.split_whitespace().map(String::from).collect(),
+                ),
+                "Deriver" => set(&mut deriver, value.to_string()),
+                "Sig" => {
+                    sigs.push(value.to_string());
+                    Ok(())
+                }
+                "CA" => set(&mut ca, value.to_string()),
+                // Nix ignores fields it doesn't know about, so we do too
+                _ => Ok(()),
+            };
+            result.map_err(|reason| malformed(format!("{reason} {field}")))?;
+        }
+
+        let missing = |field: &str| AppError::MalformedNarinfo(format!("missing {field}"));
+
+        Ok(Self {
+            store_path: store_path.ok_or_else(|| missing("StorePath"))?,
+            url: url.ok_or_else(|| missing("URL"))?,
+            compression,
+            file_hash,
+            file_size,
+            nar_hash: nar_hash.ok_or_else(|| missing("NarHash"))?,
+            nar_size: nar_size.ok_or_else(|| missing("NarSize"))?,
+            references: references.unwrap_or_default(),
+            deriver,
+            sigs,
+            ca,
+        })
+    }
+}
+
+impl std::str::FromStr for Narinfo {
+    type Err = crate::error::Report;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl std::fmt::Display for Narinfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let nix_hash = |hash: &SRIHash| match hash.to_nix_base32() {
+            Ok(hash) => hash.to_string(),
+            // Nix reads SRI hashes too, so there's no need to fail on hashes it can't print
+            Err(_) => hash.0.to_string(),
+        };
+
+        writeln!(f, "StorePath: {}", self.store_path)?;
+        writeln!(f, "URL: {}", self.url)?;
+        if let Some(compression) = &self.compression {
+            writeln!(f, "Compression: {compression}")?;
+        }
+        if let Some(file_hash) = &self.file_hash {
+            writeln!(f, "FileHash: {}", nix_hash(file_hash))?;
+        }
+        if let Some(file_size) = self.file_size {
+            writeln!(f, "FileSize: {file_size}")?;
+        }
+        writeln!(f, "NarHash: {}", nix_hash(&self.nar_hash))?;
+        writeln!(f, "NarSize: {}", self.nar_size)?;
+        writeln!(f, "References: {}", self.references.join(" "))?;
+        if let Some(deriver) = &self.deriver {
+            writeln!(f, "Deriver: {deriver}")?;
+        }
+        for sig in &self.sigs {
+            writeln!(f, "Sig: {sig}")?;
+        }
+        if let Some(ca) = &self.ca {
+            writeln!(f, "CA: {ca}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<&Narinfo> for PathInfo {
+    fn from(narinfo: &Narinfo) -> Self {
+        let store_dir = narinfo.store_dir();
+
+        PathInfo {
+            nar_hash: narinfo.nar_hash.clone(),
+            nar_size: narinfo.nar_size,
+            store_path: narinfo.store_path.clone(),
+            references: narinfo
+                .references
+                .iter()
+                .map(|reference| format!("{store_dir}/{reference}"))
+                .collect(),
+            deriver: narinfo
+                .deriver
+                .as_ref()
+                .map(|deriver| format!("{store_dir}/{deriver}")),
+            signatures: narinfo.sigs.clone(),
+            ca: narinfo.ca.clone(),
+        }
+    }
+}
+
+/// Fill a field that may only be given once.
+fn set<T>(slot: &mut Option<T>, value: T) -> Result<(), String> {
+    match slot.replace(value) {
+        Some(_) => Err(String::from("duplicate field")),
+        None => Ok(()),
+    }
+}
+
+/// Split an absolute store path into its store directory and basename.
+fn split_store_path(store_path: &str) -> Result<(&str, &str)> {
+    match store_path.rsplit_once('/') {
+        Some((store_dir, basename)) if store_dir.starts_with('/') && !basename.is_empty() => {
+            Ok((store_dir, basename))
+        }
+        _ => Err(color_eyre::eyre::eyre!("'{store_path}' is not an absolute store path").into()),
+    }
+}
@@ -0,0 +1,232 @@
+use std::path::Path;
+
+use color_eyre::eyre::WrapErr;
+
+use crate::error::{AppError, Result};
+use crate::nix::{store_path_name, PathInfo};
+
+/// Which store paths may be signed, by name, deriver, size and references.
+///
+/// Rules are tried in order, and the first one that applies to a path decides
+/// whether it may be signed. Paths no rule applies to get the default action.
+#[derive(Debug, serde_derive::Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default = "Action::deny")]
+    default: Action,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde_derive::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+impl Action {
+    fn deny() -> Self {
+        Action::Deny
+    }
+}
+
+/// A policy rule, which applies to paths matching all of its selectors.
+///
+/// Paths without a deriver match the `outputs` and `derivers` of deny rules,
+/// but not of allow rules. A deriver is only known when it's taken from the
+/// local store, never from the caller.
+///
+/// Allow rules also have constraints, and paths they apply to that don't meet
+/// every constraint are denied.
+#[derive(Debug, serde_derive::Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Rule {
+    /// What to call the rule when it denies something, defaults to its position
+    name: Option<String>,
+    action: Action,
+
+    /// Globs on the store path name, like `hello-*`
+    #[serde(default)]
+    names: Vec<String>,
+    /// Derivation output names, like `out` or `man`
+    #[serde(default)]
+    outputs: Vec<String>,
+    /// Globs on the deriver's name, like `hello-*.drv`
+    #[serde(default)]
+    derivers: Vec<String>,
+
+    max_nar_size: Option<u64>,
+    /// Globs on reference names, each of which some reference must match
+    #[serde(default)]
+    required_references: Vec<String>,
+    /// Globs on reference names, which no reference may match
+    #[serde(default)]
+    forbidden_references: Vec<String>,
+}
+
+impl Policy {
+    /// A policy that lets anything be signed, for when there's no policy file.
+    pub fn allow_all() -> Self {
+        Self {
+            default: Action::Allow,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Whether this is [`Policy::allow_all`], with nothing to check.
+    pub fn allows_everything(&self) -> bool {
+        self.default == Action::Allow && self.rules.is_empty()
+    }
+
+    #[tracing::instrument]
+    pub async fn load(policy_file: &Path) -> Result<Self> {
+        let contents = tokio::fs::read(policy_file)
+            .await
+            .wrap_err_with(|| format!("Failed to read {}", policy_file.display()))?;
+
+        Ok(serde_json::from_slice(&contents)
+            .wrap_err_with(|| format!("Failed to parse {}", policy_file.display()))?)
+    }
+
+    /// Check every path info may be signed, failing on the first that may not.
+    pub fn check_all<'a>(&self, path_infos: impl IntoIterator<Item = &'a PathInfo>) -> Result<()> {
+        path_infos
+            .into_iter()
+            .try_for_each(|path_info| self.check(path_info))
+    }
+
+    #[tracing::instrument(skip_all, fields(store_path = %path_info.store_path))]
+    pub fn check(&self, path_info: &PathInfo) -> Result<()> {
+        let denied = |rule: String, reason: String| -> Result<()> {
+            Err(AppError::PolicyDenied {
+                rule,
+                store_path: path_info.store_path.clone(),
+                reason,
+            }
+            .into())
+        };
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.applies_to(path_info) {
+                continue;
+            }
+
+            let rule_name = rule
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("#{}", index + 1));
+            tracing::debug!("policy rule '{rule_name}' applies");
+
+            if rule.action == Action::Deny {
+                return denied(rule_name, String::from("denied by rule"));
+            }
+
+            return match rule.violation(path_info) {
+                Some(reason) => denied(rule_name, reason),
+                None => Ok(()),
+            };
+        }
+
+        match self.default {
+            Action::Allow => Ok(()),
+            Action::Deny => denied(String::from("default"), String::from("no rule allows it")),
+        }
+    }
+}
+
+impl Rule {
+    fn applies_to(&self, path_info: &PathInfo) -> bool {
+        let name = store_path_name(&path_info.store_path).unwrap_or_default();
+        let deriver_name = path_info.deriver.as_deref().and_then(store_path_name);
+        // Without a deriver there's no telling a path's output or deriver, so deny rules asking
+        // about them apply, or else leaving the deriver out would get around them
+        let unknown_deriver_matches = path_info.deriver.is_none() && self.action == Action::Deny;
+
+        (self.names.is_empty() || any_glob_matches(&self.names, name))
+            && (self.outputs.is_empty()
+                || unknown_deriver_matches
+                || path_info
+                    .output_name()
+                    .is_some_and(|output| self.outputs.iter().any(|o| o == output)))
+            && (self.derivers.is_empty()
+                || unknown_deriver_matches
+                || deriver_name.is_some_and(|deriver| any_glob_matches(&self.derivers, deriver)))
+    }
+
+    /// The constraint the path info doesn't meet, if any.
+    fn violation(&self, path_info: &PathInfo) -> Option<String> {
+        if let Some(max_nar_size) = self.max_nar_size {
+            if path_info.nar_size > max_nar_size {
+                return Some(format!(
+                    "NAR size {} is over the limit of {max_nar_size}",
+                    path_info.nar_size
+                ));
+            }
+        }
+
+        let reference_names: Vec<&str> = path_info
+            .references
+            .iter()
+            .filter_map(|reference| store_path_name(reference))
+            .collect();
+
+        for required in &self.required_references {
+            if !reference_names
+                .iter()
+                .any(|name| glob_matches(required, name))
+            {
+                return Some(format!("no reference matches '{required}'"));
+            }
+        }
+
+        for forbidden in &self.forbidden_references {
+            if let Some(name) = reference_names
+                .iter()
+                .find(|name| glob_matches(forbidden, name))
+            {
+                return Some(format!("reference '{name}' matches '{forbidden}'"));
+            }
+        }
+
+        None
+    }
+}
+
+fn any_glob_matches(globs: &[String], name: &str) -> bool {
+    globs.iter().any(|glob| glob_matches(glob, name))
+}
+
+/// Match `name` against a glob where `*` matches any run of characters and `?` any one.
+fn glob_matches(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut g, mut n) = (0, 0);
+    // Where to resume after the last `*` if the rest doesn't match
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match glob.get(g) {
+            Some('*') => {
+                backtrack = Some((g, n));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the `*` swallow one more character and try again
+                Some((star, star_n)) => {
+                    g = star + 1;
+                    n = star_n + 1;
+                    backtrack = Some((star, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    glob[g..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,187 @@
+//! Keeping any one client, or all of them together, from overwhelming the server.
+
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use axum::extract::{Extension, State};
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use tokio::sync::Semaphore;
+
+use crate::auth::Caller;
+use crate::client_addr::ClientAddr;
+use crate::error::{AppError, Result};
+use crate::nix::{PathInfo, PathInfoSource};
+use crate::AppContext;
+
+/// How many buckets to keep before dropping the full ones, which are the same as no bucket.
+const MIN_PRUNE_AT: usize = 1024;
+
+/// Rate limits on where requests come from and on who makes them, with their own buckets.
+#[derive(Debug)]
+pub struct RateLimits {
+    client_addrs: RateLimiter,
+    callers: RateLimiter,
+}
+
+impl RateLimits {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            client_addrs: RateLimiter::new(rate, burst),
+            callers: RateLimiter::new(rate, burst),
+        }
+    }
+}
+
+/// A token bucket per client, each refilling at `rate` tokens a second up to `burst`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    prune_at: usize,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: f64::from(burst),
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                prune_at: MIN_PRUNE_AT,
+            }),
+        }
+    }
+
+    /// Take a token from the client's bucket, or say how long until there is one.
+    pub fn check(&self, client: &str) -> Result<()> {
+        self.check_at(client, Instant::now())
+    }
+
+    /// [`RateLimiter::check`] as of `now`.
+    pub fn check_at(&self, client: &str, now: Instant) -> Result<()> {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .map_err(|_| color_eyre::eyre::eyre!("The rate limiter lock was poisoned"))?;
+
+        if buckets.buckets.len() >= buckets.prune_at {
+            buckets
+                .buckets
+                .retain(|_, bucket| self.refilled(bucket, now) < self.burst);
+            buckets.prune_at = MIN_PRUNE_AT.max(buckets.buckets.len() * 2);
+        }
+
+        let bucket = buckets.buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            let retry_after = ((1.0 - bucket.tokens) / self.rate).ceil() as u64;
+            return Err(AppError::TooManyRequests {
+                reason: format!("{client} is over the rate limit"),
+                retry_after: retry_after.max(1),
+            }
+            .into());
+        }
+        bucket.tokens -= 1.0;
+
+        Ok(())
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// Count the request against where it came from, if requests are rate limited.
+///
+/// This is before the caller is identified, so that requests failing to authenticate, like ones
+/// guessing tokens, are limited too.
+pub async fn limit_client_addr<B>(
+    State(state): State<AppContext>,
+    client_addr: Option<Extension<ClientAddr>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response> {
+    if let (Some(rate_limits), Some(Extension(client_addr))) = (&state.rate_limits, client_addr) {
+        rate_limits.client_addrs.check(&client_addr.to_string())?;
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Count the request against the caller too, if requests are rate limited and they're known, so
+/// they can't get around the limit by coming from elsewhere.
+pub async fn limit_caller<B>(
+    State(state): State<AppContext>,
+    Extension(caller): Extension<Caller>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response> {
+    if let (Some(rate_limits), Some(key)) = (&state.rate_limits, caller.rate_limit_key()) {
+        rate_limits.callers.check(key)?;
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Turns away lookups beyond a number at once, rather than queueing up `nix` processes.
+pub struct LimitedPathInfoSource {
+    lookups: Semaphore,
+    source: Box<dyn PathInfoSource>,
+}
+
+impl LimitedPathInfoSource {
+    pub fn new(max_lookups: NonZeroUsize, source: Box<dyn PathInfoSource>) -> Self {
+        Self {
+            lookups: Semaphore::new(max_lookups.get()),
+            source,
+        }
+    }
+
+    fn busy() -> AppError {
+        AppError::TooManyRequests {
+            reason: String::from("too many path info lookups are in progress"),
+            retry_after: 1,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PathInfoSource for LimitedPathInfoSource {
+    async fn query_path_info(&self, store_path: &str) -> Result<Option<PathInfo>> {
+        let _permit = self.lookups.try_acquire().map_err(|_| Self::busy())?;
+
+        self.source.query_path_info(store_path).await
+    }
+
+    async fn query_path_infos(
+        &self,
+        store_paths: &[String],
+        recursive: bool,
+    ) -> Result<Vec<PathInfo>> {
+        let _permit = self.lookups.try_acquire().map_err(|_| Self::busy())?;
+
+        self.source.query_path_infos(store_paths, recursive).await
+    }
+}
@@ -1,7 +1,49 @@
-use crate::nix::{PathInfo, SRIHash};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use dryoc::sign::SigningKeyPair;
+
+use crate::auth::{Authorizer, Caller, Permissions};
+use crate::cli::keyring::KeyringArgs;
+use crate::keyring::{KeyStatus, Keyring};
+use crate::nix::{JsonFile, Narinfo, PathInfo, SRIHash};
 
 const SECRET_KEY_FILE_CONTENTS: &str = include_str!("../secret-key");
 const PUBLIC_KEY_FILE_CONTENTS: &str = include_str!("../public-key");
+const SECRET_KEY_FILE_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/secret-key");
+
+/// A fresh, empty directory under the system temporary directory.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "nixos-cache-signing-server-{}-{name}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Generate a new secret key named `name` and write it to a file in `dir`.
+fn write_secret_key(dir: &std::path::Path, name: &str) -> PathBuf {
+    let signing_pair: SigningKeyPair<[u8; 32], [u8; 64]> = SigningKeyPair::gen();
+    let path = dir.join(name);
+    std::fs::write(
+        &path,
+        format!("{name}:{}", STANDARD.encode(signing_pair.secret_key)),
+    )
+    .unwrap();
+    path
+}
+
+fn keyring_args(secret_key_file: Vec<PathBuf>) -> KeyringArgs {
+    KeyringArgs {
+        secret_key_file,
+        ..Default::default()
+    }
+}
 
 /*
 [
@@ -35,6 +77,31 @@ fn test_path_info() -> PathInfo {
             String::from("/nix/store/aw2fw9ag10wr9pf0qk4nk5sxi0q0bn56-glibc-2.37-8"),
             String::from("/nix/store/mdi7lvrn2mx7rfzv3fdq3v5yw8swiks6-hello-2.12.1"),
         ],
+        deriver: Some(String::from(
+            "/nix/store/vz9naq2p06k5sagqz5mxcbmff0a4d3hb-hello-2.12.1.drv",
+        )),
+        signatures: vec![String::from(
+            "cache.nixos.org-1:7guDbfaF2Q29HY0c5axhtuacfxN6uxuEqeUfncDiSvMSAWvfHVMppB89ILqV8FE58pEQ04tSbMnRhR3FGPV0AA==",
+        )],
+        ca: None,
+    }
+}
+
+/// App state with the test key and path info, and everything else off, for tests to override.
+async fn test_state() -> super::AppContextInner {
+    super::AppContextInner {
+        keyring: Keyring::load(&keyring_args(vec![SECRET_KEY_FILE_PATH.into()]))
+            .await
+            .unwrap(),
+        path_info_source: Box::new(JsonFile::from_path_infos(vec![test_path_info()])),
+        authorizer: None,
+        policy: crate::policy::Policy::allow_all(),
+        allow_malformed_fingerprints: false,
+        verify_fingerprints: false,
+        audit_log: None,
+        transparency_log: None,
+        trusted_proxies: Default::default(),
+        rate_limits: None,
     }
 }
 
@@ -55,7 +122,7 @@ async fn test_fingerprint_signing() {
     let fingerprint = path_info.fingerprint().unwrap();
 
     let expected_signature = "test-1:TS12zri2hld72xAwjPUyL0MGqcmbWtHuAFFoRCXu6PwFVd0Awqe4+wgENU7XbWm/itTWumccNX+c7DVFZqKVCA==";
-    let signature = super::sign_fingerprint(SECRET_KEY_FILE_CONTENTS, fingerprint.into())
+    let signature = super::sign_fingerprint(&[SECRET_KEY_FILE_CONTENTS], fingerprint.into())
         .await
         .expect("should have gotten a fingerprint");
 
@@ -67,3 +134,2288 @@ fn test_pubkey_generation() {
     let public_key = super::secret_key_to_public_key(SECRET_KEY_FILE_CONTENTS).unwrap();
     assert_eq!(public_key, PUBLIC_KEY_FILE_CONTENTS);
 }
+
+#[tokio::test]
+async fn test_keyring_selection() {
+    let args = keyring_args(vec![SECRET_KEY_FILE_PATH.into()]);
+    let keyring = Keyring::load(&args).await.unwrap();
+
+    assert_eq!(keyring.primary().name, "test-1");
+    assert_eq!(keyring.select(None).unwrap().name, "test-1");
+    assert_eq!(
+        keyring.select(Some("test-1")).unwrap().public_key,
+        PUBLIC_KEY_FILE_CONTENTS
+    );
+
+    let err = keyring.select(Some("missing-1")).unwrap_err();
+    assert!(format!("{err:?}").contains("No key named 'missing-1' is loaded"));
+}
+
+#[tokio::test]
+async fn test_keyring_rejects_duplicate_and_unknown_primary() {
+    let args = keyring_args(vec![
+        SECRET_KEY_FILE_PATH.into(),
+        SECRET_KEY_FILE_PATH.into(),
+    ]);
+    Keyring::load(&args)
+        .await
+        .expect_err("the same key name should not be loaded twice");
+
+    let args = KeyringArgs {
+        primary_key: Some(String::from("missing-1")),
+        ..keyring_args(vec![SECRET_KEY_FILE_PATH.into()])
+    };
+    Keyring::load(&args)
+        .await
+        .expect_err("the primary key should have to be loaded");
+}
+
+#[tokio::test]
+async fn test_keyring_rotation() {
+    let dir = test_dir("rotation");
+    let args = KeyringArgs {
+        secret_key_file: vec![
+            SECRET_KEY_FILE_PATH.into(),
+            write_secret_key(&dir, "test-2"),
+            write_secret_key(&dir, "test-0"),
+        ],
+        key_status: vec![
+            (String::from("test-2"), KeyStatus::SigningOnly),
+            (String::from("test-0"), KeyStatus::Retired),
+        ],
+        rotation: true,
+        ..Default::default()
+    };
+    let keyring = Keyring::load(&args).await.unwrap();
+
+    let trusted: Vec<&str> = keyring.trusted().map(|key| key.name.as_str()).collect();
+    assert_eq!(trusted, ["test-0", "test-1"]);
+
+    let secrets = keyring
+        .signing_secrets(None, &Caller::anonymous())
+        .await
+        .unwrap();
+    let fingerprint = test_path_info().fingerprint().unwrap();
+    let signatures = super::sign_fingerprint(&secrets, fingerprint.into())
+        .await
+        .unwrap();
+    let key_names: Vec<&str> = signatures
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(key_names, ["test-1", "test-2"]);
+
+    keyring
+        .select_signing(Some("test-0"))
+        .expect_err("retired keys should not sign");
+}
+
+#[tokio::test]
+async fn test_batch_signing() {
+    use crate::batch::BatchFormat;
+
+    let fingerprint = test_path_info().fingerprint().unwrap();
+    let expected_signature =
+        super::sign_fingerprint(&[SECRET_KEY_FILE_CONTENTS], fingerprint.clone().into())
+            .await
+            .unwrap();
+
+    let json_body = serde_json::to_vec(&[&fingerprint, &fingerprint]).unwrap();
+    let lines_body = format!("{fingerprint}\n\n{fingerprint}\n");
+    for (format, body) in [
+        (BatchFormat::Json, json_body),
+        (BatchFormat::Lines, lines_body.into_bytes()),
+    ] {
+        let fingerprints = format.parse(&body).unwrap();
+        assert_eq!(fingerprints.len(), 2);
+
+        let secret_keys = super::parse_secret_keys(&[SECRET_KEY_FILE_CONTENTS]).unwrap();
+        for fingerprint in fingerprints {
+            let signature = super::sign_with_keys(&secret_keys, fingerprint.as_bytes()).unwrap();
+            assert_eq!(signature, expected_signature);
+        }
+    }
+
+    BatchFormat::Json
+        .parse(b"{\"not\": \"an array\"}")
+        .expect_err("only JSON arrays should be accepted");
+}
+
+#[test]
+fn test_path_info_signing() {
+    let path_info = test_path_info();
+    let signatures = super::sign_path_infos(
+        &[SECRET_KEY_FILE_CONTENTS],
+        std::slice::from_ref(&path_info),
+    )
+    .expect("should have signed the path info");
+
+    assert_eq!(
+        signatures.get(&path_info.store_path).map(String::as_str),
+        Some("test-1:TS12zri2hld72xAwjPUyL0MGqcmbWtHuAFFoRCXu6PwFVd0Awqe4+wgENU7XbWm/itTWumccNX+c7DVFZqKVCA==")
+    );
+}
+
+#[tokio::test]
+async fn test_signature_verification() {
+    let args = keyring_args(vec![SECRET_KEY_FILE_PATH.into()]);
+    let keyring = Keyring::load(&args).await.unwrap();
+    let fingerprint = test_path_info().fingerprint().unwrap();
+
+    let valid = String::from("test-1:TS12zri2hld72xAwjPUyL0MGqcmbWtHuAFFoRCXu6PwFVd0Awqe4+wgENU7XbWm/itTWumccNX+c7DVFZqKVCA==");
+    let forged = String::from("test-1:AS12zri2hld72xAwjPUyL0MGqcmbWtHuAFFoRCXu6PwFVd0Awqe4+wgENU7XbWm/itTWumccNX+c7DVFZqKVCA==");
+    let unknown_key = String::from("cache.nixos.org-1:7guDbfaF2Q29HY0c5axhtuacfxN6uxuEqeUfncDiSvMSAWvfHVMppB89ILqV8FE58pEQ04tSbMnRhR3FGPV0AA==");
+    let malformed = String::from("test-1:not-base64");
+
+    let verification = super::verify_signatures(
+        &keyring,
+        fingerprint.as_bytes(),
+        vec![
+            valid.clone(),
+            forged.clone(),
+            unknown_key.clone(),
+            malformed.clone(),
+        ],
+    )
+    .unwrap();
+
+    assert_eq!(
+        verification,
+        super::Verification {
+            valid: vec![valid],
+            unknown_key: vec![unknown_key],
+            forged: vec![forged],
+            malformed: vec![malformed],
+        }
+    );
+}
+
+const TEST_NARINFO: &str = "\
+StorePath: /nix/store/mdi7lvrn2mx7rfzv3fdq3v5yw8swiks6-hello-2.12.1
+URL: nar/1s5kv2ihz9gwl7zpgkspl1qs3krmyvwkhmafa4bw1g6r7gd7l2zs.nar.xz
+Compression: xz
+FileHash: sha256:1s5kv2ihz9gwl7zpgkspl1qs3krmyvwkhmafa4bw1g6r7gd7l2zs
+FileSize: 50160
+NarHash: sha256:0nhc4jn0g0njfs3ipfcq8jg68f35sm8k67s6pcv8fjm17avcyymi
+NarSize: 226552
+References: aw2fw9ag10wr9pf0qk4nk5sxi0q0bn56-glibc-2.37-8 mdi7lvrn2mx7rfzv3fdq3v5yw8swiks6-hello-2.12.1
+Deriver: vz9naq2p06k5sagqz5mxcbmff0a4d3hb-hello-2.12.1.drv
+Sig: cache.nixos.org-1:7guDbfaF2Q29HY0c5axhtuacfxN6uxuEqeUfncDiSvMSAWvfHVMppB89ILqV8FE58pEQ04tSbMnRhR3FGPV0AA==
+";
+
+#[test]
+fn test_nix_hash_parsing() {
+    let expected = test_path_info().nar_hash.0;
+
+    for hash in [
+        "sha256:0nhc4jn0g0njfs3ipfcq8jg68f35sm8k67s6pcv8fjm17avcyymi",
+        "sha256:b17acfb63aa14a8736bb461f3351d56538649e4498b91b8776d28207ac240c5a",
+        "sha256-sXrPtjqhSoc2u0YfM1HVZThknkSYuRuHdtKCB6wkDFo=",
+    ] {
+        assert_eq!(SRIHash::from_nix_hash(hash).unwrap().0, expected, "{hash}");
+    }
+
+    SRIHash::from_nix_hash("sha256:0nhc4jn0g0njfs3ipfcq8jg68f35sm8k67s6pcv8fjm17avcyym")
+        .expect_err("digest is too short");
+    SRIHash::from_nix_hash("sha256:enhc4jn0g0njfs3ipfcq8jg68f35sm8k67s6pcv8fjm17avcyymi")
+        .expect_err("`e` is not a Nix base32 character");
+    SRIHash::from_nix_hash("md5:00000000000000000000000000000000")
+        .expect_err("md5 is not supported");
+    SRIHash::from_nix_hash("sha256-").expect_err("digest is empty");
+    SRIHash::from_nix_hash("sha256-sXrPtjqhSoc2u0YfM1HVZThknkSYuRuHdtKC")
+        .expect_err("digest is too short");
+    SRIHash::from_nix_hash("sha512-sXrPtjqhSoc2u0YfM1HVZThknkSYuRuHdtKCB6wkDFo=")
+        .expect_err("digest is too short for sha512");
+
+    let empty = SRIHash(ssri::Hash {
+        algorithm: ssri::Algorithm::Sha256,
+        digest: String::new(),
+    });
+    empty.to_nix_base32().expect_err("digest is empty");
+}
+
+#[test]
+fn test_narinfo_round_trip() {
+    let narinfo: Narinfo = TEST_NARINFO.parse().unwrap();
+
+    assert_eq!(narinfo.to_string(), TEST_NARINFO);
+    assert_eq!(narinfo.compression.as_deref(), Some("xz"));
+    assert_eq!(narinfo.file_size, Some(50160));
+    assert_eq!(narinfo.sigs.len(), 1);
+
+    let path_info = PathInfo::from(&narinfo);
+    assert_eq!(
+        path_info.fingerprint().unwrap(),
+        test_path_info().fingerprint().unwrap()
+    );
+    assert_eq!(path_info.deriver, test_path_info().deriver);
+
+    let mut from_path_info = Narinfo::from_path_info(&path_info, narinfo.url.clone()).unwrap();
+    from_path_info.compression = narinfo.compression.clone();
+    from_path_info.file_hash = narinfo.file_hash.clone();
+    from_path_info.file_size = narinfo.file_size;
+    assert_eq!(from_path_info, narinfo);
+}
+
+#[test]
+fn test_narinfo_parse_errors() {
+    use axum::response::IntoResponse;
+
+    let without = |field: &str| {
+        TEST_NARINFO
+            .lines()
+            .filter(|line| !line.starts_with(field))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+
+    for field in ["StorePath", "URL", "NarHash", "NarSize"] {
+        let err = Narinfo::parse(&without(field)).unwrap_err();
+        assert!(format!("{err:?}").contains(&format!("missing {field}")));
+    }
+
+    for (narinfo, reason) in [
+        (format!("{TEST_NARINFO}not a field"), "line 11: expected"),
+        (
+            format!("{TEST_NARINFO}NarSize: 1"),
+            "line 11: duplicate field NarSize",
+        ),
+        (
+            format!("{TEST_NARINFO}FileSize: big"),
+            "line 11: invalid size",
+        ),
+        (
+            format!("{TEST_NARINFO}FileHash: sha256:abc"),
+            "line 11: invalid hash",
+        ),
+        (
+            TEST_NARINFO.replacen("StorePath: /nix/store/", "StorePath: ", 1),
+            "line 1: invalid store path",
+        ),
+        (
+            TEST_NARINFO.replacen(
+                "NarHash: sha256:0nhc4jn0g0njfs3ipfcq8jg68f35sm8k67s6pcv8fjm17avcyymi",
+                "NarHash: sha256-",
+                1,
+            ),
+            "line 6: invalid hash",
+        ),
+        (
+            TEST_NARINFO.replacen(
+                "NarHash: sha256:0nhc4jn0g0njfs3ipfcq8jg68f35sm8k67s6pcv8fjm17avcyymi",
+                "NarHash: sha256-sXrPtjqhSoc2u0YfM1HVZThknkSYuRuHdtKC",
+                1,
+            ),
+            "line 6: invalid hash",
+        ),
+        (
+            TEST_NARINFO.replacen(
+                "FileHash: sha256:1s5kv2ihz9gwl7zpgkspl1qs3krmyvwkhmafa4bw1g6r7gd7l2zs",
+                "FileHash: sha1-",
+                1,
+            ),
+            "line 4: invalid hash",
+        ),
+    ] {
+        let err = Narinfo::parse(&narinfo).unwrap_err();
+        assert!(format!("{err:?}").contains(reason), "{err:?}");
+        assert_eq!(
+            err.into_response().status(),
+            hyper::StatusCode::BAD_REQUEST,
+            "{reason}"
+        );
+    }
+}
+
+/// Speak just enough of the daemon side of the worker protocol to answer
+/// `QueryPathInfo` for `hello` and `glibc` from [`test_path_info`].
+async fn fake_nix_daemon(mut stream: tokio::net::UnixStream) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn write_string(stream: &mut tokio::net::UnixStream, s: &str) {
+        stream.write_u64_le(s.len() as u64).await.unwrap();
+        stream.write_all(s.as_bytes()).await.unwrap();
+        stream
+            .write_all(&vec![0u8; (8 - s.len() % 8) % 8])
+            .await
+            .unwrap();
+    }
+
+    async fn read_string(stream: &mut tokio::net::UnixStream) -> String {
+        let len = stream.read_u64_le().await.unwrap() as usize;
+        let mut buf = vec![0u8; len.div_ceil(8) * 8];
+        stream.read_exact(&mut buf).await.unwrap();
+        buf.truncate(len);
+        String::from_utf8(buf).unwrap()
+    }
+
+    assert_eq!(stream.read_u64_le().await.unwrap(), 0x6e697863);
+    stream.write_u64_le(0x6478696f).await.unwrap();
+    stream.write_u64_le((1 << 8) | 35).await.unwrap();
+    assert_eq!(stream.read_u64_le().await.unwrap(), (1 << 8) | 21);
+    let _cpu_affinity = stream.read_u64_le().await.unwrap();
+    let _reserve_space = stream.read_u64_le().await.unwrap();
+    stream.write_u64_le(0x616c7473).await.unwrap();
+
+    let hello = test_path_info();
+    let glibc = "/nix/store/aw2fw9ag10wr9pf0qk4nk5sxi0q0bn56-glibc-2.37-8";
+
+    while let Ok(op) = stream.read_u64_le().await {
+        assert_eq!(op, 26, "only QueryPathInfo is supported");
+        let store_path = read_string(&mut stream).await;
+
+        // Some noise to skip over: a log line, and an activity with fields
+        stream.write_u64_le(0x6f6c6d67).await.unwrap();
+        write_string(&mut stream, "querying path info\n").await;
+        stream.write_u64_le(0x53545254).await.unwrap();
+        for value in [1, 3, 0] {
+            stream.write_u64_le(value).await.unwrap();
+        }
+        write_string(&mut stream, "").await;
+        stream.write_u64_le(2).await.unwrap();
+        stream.write_u64_le(0).await.unwrap();
+        stream.write_u64_le(42).await.unwrap();
+        stream.write_u64_le(1).await.unwrap();
+        write_string(&mut stream, "field").await;
+        stream.write_u64_le(0).await.unwrap();
+        stream.write_u64_le(0x53544f50).await.unwrap();
+        stream.write_u64_le(1).await.unwrap();
+        stream.write_u64_le(0x616c7473).await.unwrap();
+
+        let (deriver, nar_hash, nar_size, references): (&str, &str, u64, Vec<&str>) =
+            if store_path == hello.store_path {
+                (
+                    hello.deriver.as_deref().unwrap(),
+                    "b17acfb63aa14a8736bb461f3351d56538649e4498b91b8776d28207ac240c5a",
+                    hello.nar_size,
+                    vec![glibc, &hello.store_path],
+                )
+            } else if store_path == glibc {
+                (
+                    "",
+                    "0000000000000000000000000000000000000000000000000000000000000000",
+                    1,
+                    vec![glibc],
+                )
+            } else {
+                stream.write_u64_le(0).await.unwrap();
+                continue;
+            };
+
+        stream.write_u64_le(1).await.unwrap();
+        write_string(&mut stream, deriver).await;
+        write_string(&mut stream, nar_hash).await;
+        stream.write_u64_le(references.len() as u64).await.unwrap();
+        for reference in references {
+            write_string(&mut stream, reference).await;
+        }
+        stream.write_u64_le(1696097829).await.unwrap();
+        stream.write_u64_le(nar_size).await.unwrap();
+        stream.write_u64_le(0).await.unwrap();
+        stream.write_u64_le(0).await.unwrap();
+        write_string(&mut stream, "").await;
+    }
+}
+
+#[tokio::test]
+async fn test_daemon_query_path_info() {
+    use crate::nix::daemon::{DaemonClient, MAX_CONNECTIONS};
+    use crate::nix::{NixDaemon, PathInfoSource};
+
+    let socket = test_dir("daemon").join("socket");
+    let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    tokio::spawn({
+        let accepted = accepted.clone();
+        async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(fake_nix_daemon(stream));
+            }
+        }
+    });
+
+    let mut client = DaemonClient::connect(&socket).await.unwrap();
+
+    let hello = test_path_info();
+    let path_info = client
+        .query_path_info(&hello.store_path)
+        .await
+        .unwrap()
+        .expect("hello should be valid");
+    assert_eq!(
+        path_info.fingerprint().unwrap(),
+        hello.fingerprint().unwrap()
+    );
+    assert_eq!(path_info.deriver, hello.deriver);
+    assert_eq!(path_info.ca, None);
+
+    let missing = client
+        .query_path_info("/nix/store/00000000000000000000000000000000-missing")
+        .await
+        .unwrap();
+    assert!(missing.is_none());
+
+    let daemon = Arc::new(NixDaemon::new(socket));
+    let closure = daemon
+        .query_path_infos(std::slice::from_ref(&hello.store_path), true)
+        .await
+        .unwrap();
+    let closure_paths: Vec<&str> = closure.iter().map(|p| p.store_path.as_str()).collect();
+    assert_eq!(
+        closure_paths,
+        [hello.references[0].as_str(), hello.store_path.as_str()]
+    );
+    // One connection for the client above, and one reused for the whole closure
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+    // Lookups at once get connections of their own, up to a limit
+    let lookups: Vec<_> = (0..MAX_CONNECTIONS * 2)
+        .map(|_| {
+            let daemon = daemon.clone();
+            let store_path = hello.store_path.clone();
+            tokio::spawn(async move { daemon.query_path_info(&store_path).await })
+        })
+        .collect();
+    for lookup in lookups {
+        assert!(lookup.await.unwrap().unwrap().is_some());
+    }
+    assert_eq!(accepted.load(Ordering::SeqCst), 1 + MAX_CONNECTIONS);
+}
+
+#[tokio::test]
+async fn test_db_query_path_info() {
+    use crate::nix::{NixDb, PathInfoSource};
+
+    let db = test_dir("db").join("db.sqlite");
+    let hello = test_path_info();
+    let glibc = &hello.references[0];
+
+    let connection = rusqlite::Connection::open(&db).unwrap();
+    connection
+        .execute_batch(
+            "
+            create table ValidPaths (
+                id integer primary key autoincrement not null,
+                path text unique not null,
+                hash text not null,
+                registrationTime integer not null,
+                deriver text,
+                narSize integer,
+                ultimate integer,
+                sigs text,
+                ca text
+            );
+            create table Refs (
+                referrer integer not null,
+                reference integer not null,
+                primary key (referrer, reference)
+            );
+            ",
+        )
+        .unwrap();
+    connection
+        .execute(
+            "insert into ValidPaths (id, path, hash, registrationTime, deriver, narSize, sigs) values (1, ?, ?, 1696097829, ?, ?, ?)",
+            rusqlite::params![
+                hello.store_path,
+                "sha256:b17acfb63aa14a8736bb461f3351d56538649e4498b91b8776d28207ac240c5a",
+                hello.deriver,
+                hello.nar_size,
+                hello.signatures.join(" "),
+            ],
+        )
+        .unwrap();
+    connection
+        .execute(
+            "insert into ValidPaths (id, path, hash, registrationTime, narSize, ca) values (2, ?, ?, 1696097829, 1, '')",
+            rusqlite::params![
+                glibc,
+                "sha256:0000000000000000000000000000000000000000000000000000000000000000",
+            ],
+        )
+        .unwrap();
+    connection
+        .execute_batch("insert into Refs values (1, 2), (1, 1), (2, 2);")
+        .unwrap();
+    drop(connection);
+
+    let source = NixDb::new(db);
+    let path_infos = source
+        .query_path_infos(std::slice::from_ref(&hello.store_path), false)
+        .await
+        .unwrap();
+    assert_eq!(path_infos.len(), 1);
+    assert_eq!(
+        path_infos[0].fingerprint().unwrap(),
+        hello.fingerprint().unwrap()
+    );
+    assert_eq!(path_infos[0].deriver, hello.deriver);
+    assert_eq!(path_infos[0].signatures, hello.signatures);
+
+    let closure = source
+        .query_path_infos(std::slice::from_ref(&hello.store_path), true)
+        .await
+        .unwrap();
+    let closure_paths: Vec<&str> = closure.iter().map(|p| p.store_path.as_str()).collect();
+    assert_eq!(closure_paths, [glibc.as_str(), hello.store_path.as_str()]);
+    assert_eq!(closure[0].ca, None);
+
+    source
+        .query_path_infos(&[String::from("/nix/store/missing")], false)
+        .await
+        .expect_err("missing paths are not valid");
+}
+
+#[tokio::test]
+async fn test_sign_store_path_handler() {
+    use axum::extract::{Query, State};
+    use axum::response::IntoResponse;
+    use axum::Extension;
+
+    let dir = test_dir("sign-store-path-handler");
+    let hello = test_path_info();
+    let glibc = PathInfo {
+        store_path: hello.references[0].clone(),
+        references: vec![hello.references[0].clone()],
+        deriver: None,
+        signatures: Vec::new(),
+        ..test_path_info()
+    };
+    let path_info_json = dir.join("path-info.json");
+    std::fs::write(
+        &path_info_json,
+        serde_json::json!([
+            {
+                "path": hello.store_path,
+                "narHash": "sha256-sXrPtjqhSoc2u0YfM1HVZThknkSYuRuHdtKCB6wkDFo=",
+                "narSize": hello.nar_size,
+                "references": hello.references,
+            },
+            {
+                "path": glibc.store_path,
+                "narHash": "sha256-sXrPtjqhSoc2u0YfM1HVZThknkSYuRuHdtKCB6wkDFo=",
+                "narSize": glibc.nar_size,
+                "references": glibc.references,
+            },
+        ])
+        .to_string(),
+    )
+    .unwrap();
+
+    let state = Arc::new(super::AppContextInner {
+        path_info_source: Box::new(JsonFile::load(&path_info_json).unwrap()),
+        ..test_state().await
+    });
+
+    let query = |recursive| super::SignStorePathQuery {
+        key: None,
+        recursive,
+    };
+    let response = super::sign_store_path(
+        State(state.clone()),
+        Extension(Caller::anonymous()),
+        Query(query(false)),
+        hello.store_path.clone(),
+    )
+    .await
+    .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "test-1:TS12zri2hld72xAwjPUyL0MGqcmbWtHuAFFoRCXu6PwFVd0Awqe4+wgENU7XbWm/itTWumccNX+c7DVFZqKVCA==");
+
+    let response = super::sign_store_path(
+        State(state.clone()),
+        Extension(Caller::anonymous()),
+        Query(query(true)),
+        hello.store_path.clone(),
+    )
+    .await
+    .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let signatures: std::collections::BTreeMap<String, String> =
+        serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        signatures.keys().collect::<Vec<_>>(),
+        [&glibc.store_path, &hello.store_path]
+    );
+    for path_info in [&glibc, &hello] {
+        let verification = super::verify_signatures(
+            &state.keyring,
+            path_info.fingerprint().unwrap().as_bytes(),
+            vec![signatures[&path_info.store_path].clone()],
+        )
+        .unwrap();
+        assert_eq!(verification.valid.len(), 1, "{}", path_info.store_path);
+    }
+
+    let response = super::sign_store_path_batch(
+        State(state.clone()),
+        Extension(Caller::anonymous()),
+        Query(query(true)),
+        axum::http::HeaderMap::new(),
+        hyper::body::Bytes::from(hello.store_path.clone()),
+    )
+    .await
+    .unwrap()
+    .into_response();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let batch_signatures: std::collections::BTreeMap<String, String> =
+        serde_json::from_slice(&body).unwrap();
+    assert_eq!(batch_signatures, signatures);
+
+    let response = super::sign_store_path(
+        State(state),
+        Extension(Caller::anonymous()),
+        Query(query(false)),
+        String::from("/nix/store/00000000000000000000000000000000-missing"),
+    )
+    .await
+    .unwrap_err()
+    .into_response();
+    assert_eq!(response.status(), hyper::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_bearer_token_authorization() {
+    use axum::extract::{Query, State};
+    use axum::http::header::AUTHORIZATION;
+    use axum::http::HeaderMap;
+    use axum::response::IntoResponse;
+    use axum::Extension;
+
+    let token_hash = |token: &str| {
+        let integrity = ssri::IntegrityOpts::new()
+            .algorithm(ssri::Algorithm::Sha256)
+            .chain(token)
+            .result();
+        format!("sha256:{}", integrity.to_hex().1)
+    };
+    let rules: Vec<Permissions> = serde_json::from_value(serde_json::json!([
+        {
+            "name": "builder",
+            "token": token_hash("builder-token"),
+            "operations": ["sign-store-path", "publickey"],
+            "keys": ["test-1"],
+        },
+        {
+            "token": token_hash("anything-token"),
+            "operations": ["sign", "sign-store-path", "publickey", "verify"],
+        },
+    ]))
+    .unwrap();
+    let authorizer = Authorizer::from_rules(rules).unwrap();
+
+    let headers = |token: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+        headers
+    };
+
+    let response = authorizer
+        .identify(None, &HeaderMap::new())
+        .unwrap_err()
+        .into_response();
+    assert_eq!(response.status(), hyper::StatusCode::UNAUTHORIZED);
+    let response = authorizer
+        .identify(None, &headers("wrong-token"))
+        .unwrap_err()
+        .into_response();
+    assert_eq!(response.status(), hyper::StatusCode::UNAUTHORIZED);
+
+    let builder = authorizer
+        .identify(None, &headers("builder-token"))
+        .unwrap();
+    assert_eq!(builder.identity, "builder");
+
+    let dir = test_dir("bearer-token-authorization");
+    let args = KeyringArgs {
+        secret_key_file: vec![
+            SECRET_KEY_FILE_PATH.into(),
+            write_secret_key(&dir, "test-2"),
+        ],
+        ..Default::default()
+    };
+    let keyring = Keyring::load(&args).await.unwrap();
+
+    assert!(keyring.signing_secrets(None, &builder).await.is_ok());
+    let response = keyring
+        .signing_secrets(Some("test-2"), &builder)
+        .await
+        .unwrap_err()
+        .into_response();
+    assert_eq!(response.status(), hyper::StatusCode::FORBIDDEN);
+
+    let anything = authorizer
+        .identify(None, &headers("anything-token"))
+        .unwrap();
+    assert!(anything.identity.starts_with("token "));
+    assert!(keyring
+        .signing_secrets(Some("test-2"), &anything)
+        .await
+        .is_ok());
+
+    // Trusted keys are only listed to callers allowed to use them
+    let state = Arc::new(super::AppContextInner {
+        keyring,
+        ..test_state().await
+    });
+    let trusted_keys = |caller: Caller| {
+        let state = state.clone();
+        async move {
+            let response = super::public_key(
+                State(state),
+                Extension(caller),
+                Query(super::PublicKeyQuery {
+                    key: None,
+                    trusted: true,
+                }),
+            )
+            .await
+            .unwrap()
+            .into_response();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+    };
+    assert_eq!(
+        trusted_keys(builder.clone()).await,
+        PUBLIC_KEY_FILE_CONTENTS.trim()
+    );
+    assert_eq!(trusted_keys(anything.clone()).await.lines().count(), 2);
+
+    // Routes are only served to callers allowed their operation, and routes without one to nobody
+    let state = Arc::new(super::AppContextInner {
+        authorizer: Some(authorizer),
+        ..test_state().await
+    });
+    let app = axum::Router::new()
+        .route("/publickey", axum::routing::get(|| async { "public key" }))
+        .route("/verify", axum::routing::get(|| async { "verified" }))
+        .route("/unmapped", axum::routing::get(|| async { "unmapped" }))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            crate::auth::authorize,
+        ))
+        .with_state(state);
+    let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    let get = |path: &'static str, token: &'static str| async move {
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let authorization = format!("Bearer {token}");
+        http_get(stream, path, &[("Authorization", &authorization)])
+            .await
+            .unwrap()
+    };
+    assert!(get("/publickey", "builder-token")
+        .await
+        .starts_with("HTTP/1.1 200"));
+    assert!(get("/verify", "builder-token")
+        .await
+        .starts_with("HTTP/1.1 403"));
+    assert!(get("/verify", "anything-token")
+        .await
+        .starts_with("HTTP/1.1 200"));
+    assert!(get("/unmapped", "anything-token")
+        .await
+        .starts_with("HTTP/1.1 403"));
+}
+
+struct TestCertificate {
+    certificate: rcgen::Certificate,
+    /// Serializing `certificate` again would self-sign it, so keep the signed one
+    der: Vec<u8>,
+}
+
+/// Generate a certificate for `san`, signed by `ca` or else self-signed, writing it and its key to `dir`.
+fn write_certificate(
+    dir: &std::path::Path,
+    name: &str,
+    san: rcgen::SanType,
+    ca: Option<&TestCertificate>,
+) -> TestCertificate {
+    let mut params = rcgen::CertificateParams::default();
+    params.subject_alt_names = vec![san];
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, name);
+    if ca.is_none() {
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    }
+    let certificate = rcgen::Certificate::from_params(params).unwrap();
+
+    let der = match ca {
+        Some(ca) => certificate
+            .serialize_der_with_signer(&ca.certificate)
+            .unwrap(),
+        None => certificate.serialize_der().unwrap(),
+    };
+    let pem = format!(
+        "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+        STANDARD.encode(&der)
+    );
+    std::fs::write(dir.join(format!("{name}.pem")), pem).unwrap();
+    std::fs::write(
+        dir.join(format!("{name}.key")),
+        certificate.serialize_private_key_pem(),
+    )
+    .unwrap();
+
+    TestCertificate { certificate, der }
+}
+
+/// Send a `GET` with `headers` over `stream`, and read the whole response.
+async fn http_get<S>(mut stream: S, path: &str, headers: &[(&str, &str)]) -> std::io::Result<String>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n");
+    for (name, value) in headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+
+    Ok(response)
+}
+
+async fn https_get(
+    addr: std::net::SocketAddr,
+    ca: &TestCertificate,
+    client: Option<&TestCertificate>,
+) -> std::io::Result<String> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&rustls::Certificate(ca.der.clone())).unwrap();
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let config = match client {
+        Some(client) => config
+            .with_client_auth_cert(
+                vec![rustls::Certificate(client.der.clone())],
+                rustls::PrivateKey(client.certificate.serialize_private_key_der()),
+            )
+            .unwrap(),
+        None => config.with_no_client_auth(),
+    };
+
+    let stream = tokio::net::TcpStream::connect(addr).await?;
+    let stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+        .connect("localhost".try_into().unwrap(), stream)
+        .await?;
+
+    http_get(stream, "/", &[]).await
+}
+
+#[tokio::test]
+async fn test_mutual_tls() {
+    use axum::extract::ConnectInfo;
+    use axum::http::HeaderMap;
+    use rcgen::SanType;
+
+    use crate::cli::tls::TlsArgs;
+    use crate::listener::tls::ClientCertificate;
+    use crate::listener::{ListenAddr, Listener, Peer, PeerAddr};
+
+    let dir = test_dir("mutual-tls");
+    let server_ca = write_certificate(&dir, "server-ca", SanType::DnsName("ca".into()), None);
+    write_certificate(
+        &dir,
+        "server",
+        SanType::DnsName("localhost".into()),
+        Some(&server_ca),
+    );
+    let client_ca = write_certificate(&dir, "client-ca", SanType::DnsName("ca".into()), None);
+    let client = write_certificate(
+        &dir,
+        "client",
+        SanType::DnsName("builder.example.org".into()),
+        Some(&client_ca),
+    );
+    let stranger_ca = write_certificate(&dir, "stranger-ca", SanType::DnsName("ca".into()), None);
+    let stranger = write_certificate(
+        &dir,
+        "stranger",
+        SanType::DnsName("stranger.example.org".into()),
+        Some(&stranger_ca),
+    );
+
+    let tls = TlsArgs {
+        tls_cert: Some(dir.join("server.pem")),
+        tls_key: Some(dir.join("server.key")),
+        tls_client_ca: Some(dir.join("client-ca.pem")),
+        tls_require_client_cert: true,
+    }
+    .acceptor()
+    .unwrap()
+    .unwrap();
+    let listener = Listener::bind(
+        &ListenAddr::Tcp(([127, 0, 0, 1], 0).into()),
+        Some(tls.clone()),
+    )
+    .await
+    .unwrap();
+    let [ListenAddr::Tcp(addr)] = *listener.local_addrs() else {
+        unreachable!()
+    };
+
+    let app = axum::Router::new().route(
+        "/",
+        axum::routing::get(|ConnectInfo(peer): ConnectInfo<Peer>| async move {
+            peer.certificate.unwrap().names.join(",")
+        }),
+    );
+    tokio::spawn(
+        axum::Server::builder(listener).serve(app.into_make_service_with_connect_info::<Peer>()),
+    );
+
+    let response = https_get(addr, &server_ca, Some(&client)).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("builder.example.org,client"));
+
+    // No certificate, or one from the wrong CA
+    assert!(https_get(addr, &server_ca, None).await.is_err());
+    assert!(https_get(addr, &server_ca, Some(&stranger)).await.is_err());
+
+    // Replace the server certificate and pick it up without restarting
+    let new_server_ca = write_certificate(&dir, "server-ca", SanType::DnsName("ca".into()), None);
+    write_certificate(
+        &dir,
+        "server",
+        SanType::DnsName("localhost".into()),
+        Some(&new_server_ca),
+    );
+    assert!(https_get(addr, &new_server_ca, Some(&client))
+        .await
+        .is_err());
+    tls.reload().unwrap();
+    assert!(https_get(addr, &new_server_ca, Some(&client)).await.is_ok());
+    assert!(https_get(addr, &server_ca, Some(&client)).await.is_err());
+
+    let rules: Vec<Permissions> = serde_json::from_value(serde_json::json!([
+        {
+            "certificate": "builder.example.org",
+            "operations": ["sign-store-path"],
+        },
+    ]))
+    .unwrap();
+    let authorizer = Authorizer::from_rules(rules).unwrap();
+    let peer = Peer {
+        addr: PeerAddr::Tcp(addr),
+        certificate: Some(Arc::new(ClientCertificate {
+            names: vec![String::from("builder.example.org"), String::from("client")],
+        })),
+    };
+    let caller = authorizer.identify(Some(&peer), &HeaderMap::new()).unwrap();
+    assert_eq!(caller.identity, "certificate builder.example.org");
+}
+
+#[tokio::test]
+async fn test_unix_socket_peer_credentials() {
+    use std::os::unix::fs::MetadataExt;
+
+    use axum::extract::ConnectInfo;
+    use axum::http::HeaderMap;
+
+    use crate::listener::{ListenAddr, Listener, Peer, PeerAddr};
+
+    let dir = test_dir("unix-socket-peer-credentials");
+    let socket = dir.join("socket");
+    // A stale socket from a previous run shouldn't stop us from listening
+    std::os::unix::net::UnixListener::bind(&socket).unwrap();
+    let listener = Listener::bind(&ListenAddr::Unix(socket.clone()), None)
+        .await
+        .unwrap();
+
+    let app = axum::Router::new().route(
+        "/",
+        axum::routing::get(|ConnectInfo(peer): ConnectInfo<Peer>| async move { peer.source() }),
+    );
+    tokio::spawn(
+        axum::Server::builder(listener).serve(app.into_make_service_with_connect_info::<Peer>()),
+    );
+
+    let stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+    let response = http_get(stream, "/", &[]).await.unwrap();
+
+    // Nor should it let us take over the socket from someone still listening on it
+    let err = Listener::bind(&ListenAddr::Unix(socket.clone()), None)
+        .await
+        .err()
+        .unwrap();
+    assert!(format!("{err:?}").contains("in use"), "{err:?}");
+
+    // We own the directory we just made, so we're the ones who connected
+    let metadata = std::fs::metadata(&dir).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with(&format!("uid {}", metadata.uid())));
+
+    let rules: Vec<Permissions> = serde_json::from_value(serde_json::json!([
+        { "uid": metadata.uid() + 1, "operations": ["sign"] },
+        { "gid": metadata.gid(), "operations": ["sign-store-path"] },
+    ]))
+    .unwrap();
+    let authorizer = Authorizer::from_rules(rules).unwrap();
+    let peer = Peer {
+        addr: PeerAddr::Unix {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        },
+        certificate: None,
+    };
+    let caller = authorizer.identify(Some(&peer), &HeaderMap::new()).unwrap();
+    assert_eq!(caller.identity, format!("gid {}", metadata.gid()));
+}
+
+#[tokio::test]
+async fn test_socket_activation() {
+    use std::os::fd::IntoRawFd;
+
+    use axum::extract::ConnectInfo;
+
+    use crate::listener::systemd::socket_from_fd;
+    use crate::listener::{ListenAddr, Listener, Peer};
+
+    let dir = test_dir("socket-activation");
+    let socket = dir.join("socket");
+    // Stand in for systemd, opening the sockets and handing over their file descriptors
+    let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let tcp_addr = tcp.local_addr().unwrap();
+    let tcp = unsafe { socket_from_fd(tcp.into_raw_fd()) }.unwrap();
+    let unix = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+    let unix = unsafe { socket_from_fd(unix.into_raw_fd()) }.unwrap();
+
+    let listener = Listener::from_activated(
+        vec![(String::from("http"), tcp), (String::from("unix"), unix)],
+        None,
+    )
+    .unwrap();
+    assert_eq!(
+        listener.local_addrs(),
+        [ListenAddr::Tcp(tcp_addr), ListenAddr::Unix(socket.clone())]
+    );
+
+    let app = axum::Router::new().route(
+        "/",
+        axum::routing::get(|ConnectInfo(peer): ConnectInfo<Peer>| async move { peer.source() }),
+    );
+    tokio::spawn(
+        axum::Server::builder(listener).serve(app.into_make_service_with_connect_info::<Peer>()),
+    );
+
+    let stream = tokio::net::TcpStream::connect(tcp_addr).await.unwrap();
+    let response = http_get(stream, "/", &[]).await.unwrap();
+    assert!(response.ends_with("127.0.0.1"));
+
+    let stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+    let response = http_get(stream, "/", &[]).await.unwrap();
+    assert!(response.contains("uid "));
+}
+
+#[test]
+fn test_signing_policy() {
+    use axum::response::IntoResponse;
+
+    use crate::policy::Policy;
+
+    let policy: Policy = serde_json::from_value(serde_json::json!({
+        "rules": [
+            { "name": "no-debug", "action": "deny", "outputs": ["debug"] },
+            {
+                "name": "hello",
+                "action": "allow",
+                "names": ["hello-*"],
+                "derivers": ["hello-*.drv"],
+                "maxNarSize": 300000,
+                "requiredReferences": ["glibc-*"],
+                "forbiddenReferences": ["*-source"],
+            },
+        ],
+    }))
+    .unwrap();
+
+    let hello = test_path_info();
+    assert_eq!(hello.output_name(), Some("out"));
+    policy.check(&hello).unwrap();
+
+    let denied_by = |path_info: &PathInfo| {
+        let err = policy.check(path_info).unwrap_err();
+        let message = format!("{err:?}");
+        assert_eq!(err.into_response().status(), hyper::StatusCode::FORBIDDEN);
+        message
+    };
+
+    let mut debug = test_path_info();
+    debug.store_path = hello
+        .store_path
+        .replace("hello-2.12.1", "hello-2.12.1-debug");
+    assert_eq!(debug.output_name(), Some("debug"));
+    assert!(denied_by(&debug).contains("Policy rule 'no-debug'"));
+
+    let mut big = test_path_info();
+    big.nar_size = 300001;
+    assert!(denied_by(&big).contains("Policy rule 'hello'"));
+
+    let mut orphan = test_path_info();
+    orphan.references.clear();
+    assert!(denied_by(&orphan).contains("Policy rule 'hello'"));
+
+    let mut sourced = test_path_info();
+    sourced.references.push(String::from(
+        "/nix/store/00000000000000000000000000000000-hello-source",
+    ));
+    assert!(denied_by(&sourced).contains("Policy rule 'hello'"));
+
+    // Leaving out the deriver doesn't get around deny rules asking about it
+    let mut underived = test_path_info();
+    underived.deriver = None;
+    assert_eq!(underived.output_name(), None);
+    assert!(denied_by(&underived).contains("Policy rule 'no-debug'"));
+
+    let mut other = test_path_info();
+    other.store_path = hello.store_path.replace("hello-2.12.1", "goodbye-1.0");
+    assert!(denied_by(&other).contains("Policy rule 'default'"));
+
+    assert!(Policy::allow_all().check(&other).is_ok());
+}
+
+#[test]
+fn test_fingerprint_parsing() {
+    use crate::nix::Fingerprint;
+
+    let path_info = test_path_info();
+    let fingerprint = path_info.fingerprint().unwrap();
+    let parsed = Fingerprint::parse(&fingerprint).unwrap();
+    assert_eq!(parsed.store_path, path_info.store_path);
+    assert_eq!(parsed.nar_hash, path_info.nar_hash);
+    assert_eq!(parsed.nar_size, path_info.nar_size);
+    assert_eq!(parsed.references, path_info.references);
+
+    let mut no_references = test_path_info();
+    no_references.references.clear();
+    assert!(Fingerprint::parse(&no_references.fingerprint().unwrap()).is_ok());
+
+    let hash = "sha256:1q8w6jhxd9ilzhcs3qc6aik0fjkf56w0ggmij8qz3x3b79as3zgd";
+    let hello = "/nix/store/mdi7lvrn2mx7rfzv3fdq3v5yw8swiks6-hello-2.12.1";
+    let malformed = [
+        String::from("not a fingerprint"),
+        format!("2;{hello};{hash};226552;"),
+        format!("1;hello;{hash};226552;"),
+        format!("1;/nix/store/mdi7lvrn2mx7rfzv3fdq3v5yw8swiks6;{hash};226552;"),
+        format!("1;/nix/store/mdi7lvrn2mx7rfzv3fdq3v5yw8swike6-hello;{hash};226552;"),
+        format!("1;/nix/store/mdi7lvrn2mx7rfzv3fdq3v5yw8swiks6-.hello;{hash};226552;"),
+        format!("1;/nix/store/mdi7lvrn2mx7rfzv3fdq3v5yw8swiks6-hel/lo;{hash};226552;"),
+        format!("1;{hello};md5:1q8w6jhxd9ilzhcs3qc6aik0fjkf56w0;226552;"),
+        format!("1;{hello};sha256:b07acfb63aa14a8736bb461f3351d5653864918d9b1b8774db4a2807ac24a0c5;226552;"),
+        format!("1;{hello};sha256-sXrPtjqhSoc2u0YfM1HVZThknkSYuRuHdtKCB6wkDFo=;226552;"),
+        format!("1;{hello};{hash};-1;"),
+        format!("1;{hello};{hash};+226552;"),
+        format!("1;{hello};{hash};226552;{hello},"),
+        format!("1;{hello};{hash};226552;/gnu/store/aw2fw9ag10wr9pf0qk4nk5sxi0q0bn56-glibc-2.37-8"),
+        format!("1;{hello};{hash};226552;{hello};extra"),
+    ];
+    for fingerprint in malformed {
+        assert!(
+            Fingerprint::parse(&fingerprint).is_err(),
+            "'{fingerprint}' should have been rejected"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_verified_fingerprint_signing() {
+    use axum::extract::{Query, State};
+    use axum::response::IntoResponse;
+    use axum::Extension;
+
+    use crate::keyring::KeySelection;
+
+    let hello = test_path_info();
+    let state = Arc::new(super::AppContextInner {
+        verify_fingerprints: true,
+        ..test_state().await
+    });
+    let sign = |path_info: PathInfo| {
+        super::sign(
+            State(state.clone()),
+            Extension(Caller::anonymous()),
+            Query(KeySelection { key: None }),
+            path_info.fingerprint().unwrap().into(),
+        )
+    };
+
+    let response = sign(hello.clone()).await.unwrap().into_response();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "test-1:TS12zri2hld72xAwjPUyL0MGqcmbWtHuAFFoRCXu6PwFVd0Awqe4+wgENU7XbWm/itTWumccNX+c7DVFZqKVCA==");
+
+    let mut reordered = hello.clone();
+    reordered.references.reverse();
+    assert!(sign(reordered).await.is_ok());
+
+    let mismatched = [
+        PathInfo {
+            nar_size: hello.nar_size + 1,
+            ..hello.clone()
+        },
+        PathInfo {
+            nar_hash: SRIHash::from_nix_hash(
+                "sha256:0000000000000000000000000000000000000000000000000000",
+            )
+            .unwrap(),
+            ..hello.clone()
+        },
+        PathInfo {
+            references: hello.references[1..].to_vec(),
+            ..hello.clone()
+        },
+    ];
+    for path_info in mismatched {
+        let err = sign(path_info).await.err().unwrap();
+        assert!(format!("{err:?}").contains("doesn't match the local store"));
+        assert_eq!(err.into_response().status(), hyper::StatusCode::CONFLICT);
+    }
+
+    let missing = PathInfo {
+        store_path: String::from("/nix/store/00000000000000000000000000000000-missing"),
+        ..hello
+    };
+    let response = sign(missing).await.err().unwrap().into_response();
+    assert_eq!(response.status(), hyper::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_verified_narinfo_signing() {
+    use axum::extract::{Query, State};
+    use axum::response::IntoResponse;
+    use axum::Extension;
+
+    use crate::keyring::KeySelection;
+
+    let state = Arc::new(super::AppContextInner {
+        verify_fingerprints: true,
+        ..test_state().await
+    });
+    let sign = |narinfo: String| {
+        super::sign_narinfo(
+            State(state.clone()),
+            Extension(Caller::anonymous()),
+            Query(KeySelection { key: None }),
+            narinfo,
+        )
+    };
+
+    let response = sign(TEST_NARINFO.to_string())
+        .await
+        .unwrap()
+        .into_response();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(String::from_utf8(body.to_vec())
+        .unwrap()
+        .ends_with("Sig: test-1:TS12zri2hld72xAwjPUyL0MGqcmbWtHuAFFoRCXu6PwFVd0Awqe4+wgENU7XbWm/itTWumccNX+c7DVFZqKVCA==\n"));
+
+    let mismatched = TEST_NARINFO.replace("NarSize: 226552", "NarSize: 226553");
+    let err = sign(mismatched).await.err().unwrap();
+    assert!(format!("{err:?}").contains("doesn't match the local store"));
+    assert_eq!(err.into_response().status(), hyper::StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn test_fingerprint_signing_policy() {
+    use axum::extract::{Query, State};
+    use axum::response::IntoResponse;
+    use axum::Extension;
+
+    use crate::keyring::KeySelection;
+    use crate::policy::Policy;
+
+    let policy = || -> Policy {
+        serde_json::from_value(serde_json::json!({
+            "rules": [
+                { "name": "no-man", "action": "deny", "outputs": [ "man" ] },
+                { "name": "hello", "action": "allow", "names": [ "hello-*" ], "maxNarSize": 1000000 }
+            ]
+        }))
+        .unwrap()
+    };
+    let sign = |state: &super::AppContext, fingerprint: &str| {
+        super::sign(
+            State(state.clone()),
+            Extension(Caller::anonymous()),
+            Query(KeySelection { key: None }),
+            fingerprint.to_string().into(),
+        )
+    };
+
+    let hello = test_path_info();
+    let big = PathInfo {
+        nar_size: 2000000,
+        ..hello.clone()
+    };
+    let man = PathInfo {
+        store_path: format!("{}-man", hello.store_path),
+        ..hello.clone()
+    };
+    let denied_by = |err: crate::error::Report| {
+        let message = format!("{err:?}");
+        assert_eq!(err.into_response().status(), hyper::StatusCode::FORBIDDEN);
+        message
+    };
+
+    let state = Arc::new(super::AppContextInner {
+        policy: policy(),
+        ..test_state().await
+    });
+    // Without the deriver, there's no telling any of them isn't the `man` output
+    for path_info in [&hello, &big, &man] {
+        let err = sign(&state, &path_info.fingerprint().unwrap())
+            .await
+            .err()
+            .unwrap();
+        assert!(denied_by(err).contains("Policy rule 'no-man'"));
+    }
+
+    // Nor for narinfos, whose `Deriver:` is only the caller's word
+    let sign_narinfo = |state: &super::AppContext, narinfo: &str| {
+        super::sign_narinfo(
+            State(state.clone()),
+            Extension(Caller::anonymous()),
+            Query(KeySelection { key: None }),
+            narinfo.to_string(),
+        )
+    };
+    let underived = TEST_NARINFO
+        .lines()
+        .filter(|line| !line.starts_with("Deriver: "))
+        .collect::<Vec<_>>()
+        .join("\n");
+    // The `man` output, with a deriver making it out to be the `out` of some other derivation
+    let forged_deriver = TEST_NARINFO
+        .replacen("-hello-2.12.1\n", "-hello-2.12.1-man\n", 1)
+        .replacen("-hello-2.12.1.drv", "-hello-2.12.1-man.drv", 1);
+    assert!(forged_deriver.contains(&format!("StorePath: {}\n", man.store_path)));
+    for narinfo in [TEST_NARINFO, &underived, &forged_deriver] {
+        let err = sign_narinfo(&state, narinfo).await.err().unwrap();
+        assert!(denied_by(err).contains("Policy rule 'no-man'"));
+    }
+
+    let state = Arc::new(super::AppContextInner {
+        policy: policy(),
+        path_info_source: Box::new(JsonFile::from_path_infos(vec![hello.clone(), man.clone()])),
+        verify_fingerprints: true,
+        ..test_state().await
+    });
+    assert!(sign(&state, &hello.fingerprint().unwrap()).await.is_ok());
+    let err = sign(&state, &man.fingerprint().unwrap())
+        .await
+        .err()
+        .unwrap();
+    assert!(denied_by(err).contains("Policy rule 'no-man'"));
+    // The local store's deriver is used instead of the forged one
+    assert!(sign_narinfo(&state, TEST_NARINFO).await.is_ok());
+    let err = sign_narinfo(&state, &forged_deriver).await.err().unwrap();
+    assert!(denied_by(err).contains("Policy rule 'no-man'"));
+
+    // Malformed fingerprints have nothing to hold a policy to
+    let state = Arc::new(super::AppContextInner {
+        policy: policy(),
+        allow_malformed_fingerprints: true,
+        ..test_state().await
+    });
+    assert!(sign(&state, "anything").await.is_err());
+    let err = sign(&state, &big.fingerprint().unwrap())
+        .await
+        .err()
+        .unwrap();
+    assert!(denied_by(err).contains("Policy rule 'no-man'"));
+    let state = Arc::new(super::AppContextInner {
+        allow_malformed_fingerprints: true,
+        ..test_state().await
+    });
+    assert!(sign(&state, "anything").await.is_ok());
+}
+
+#[tokio::test]
+async fn test_audit_log_hash_chain() {
+    use crate::audit::{self, AuditLog, Record};
+
+    let dir = test_dir("audit-log");
+    let path = dir.join("audit.jsonl");
+    let fingerprint = test_path_info().fingerprint().unwrap();
+    let mut caller = Caller::anonymous();
+    caller.source = Some(String::from("192.0.2.1"));
+
+    let audit_log = AuditLog::open(&path).await.unwrap();
+    audit_log
+        .record(
+            &caller,
+            [(
+                fingerprint.as_bytes(),
+                "test-1:c2lnbmF0dXJlIDE=\ntest-2:c2lnbmF0dXJlIDI=",
+            )],
+        )
+        .await
+        .unwrap();
+    drop(audit_log);
+
+    // Reopening carries on the chain
+    let audit_log = AuditLog::open(&path).await.unwrap();
+    audit_log
+        .record(
+            &caller,
+            [(&b"not a fingerprint"[..], "test-1:c2lnbmF0dXJlIDM=")],
+        )
+        .await
+        .unwrap();
+
+    let head = audit::verify(&path).await.unwrap();
+    assert_eq!(head.records, 3);
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let records: Vec<Record> = contents
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records[0].identity, "anonymous");
+    assert_eq!(records[0].source.as_deref(), Some("192.0.2.1"));
+    assert_eq!(records[0].key, "test-1");
+    assert_eq!(records[1].key, "test-2");
+    assert_eq!(
+        records[1].store_path.as_deref(),
+        Some("/nix/store/mdi7lvrn2mx7rfzv3fdq3v5yw8swiks6-hello-2.12.1")
+    );
+    assert_eq!(
+        records[1].nar_hash.as_deref(),
+        Some("sha256:0nhc4jn0g0njfs3ipfcq8jg68f35sm8k67s6pcv8fjm17avcyymi")
+    );
+    assert_eq!(records[2].store_path, None);
+    assert_eq!(records[2].signature, "c2lnbmF0dXJlIDM=");
+    assert_eq!(records[0].previous, "0".repeat(64));
+
+    let tampered = contents.replacen("c2lnbmF0dXJlIDI=", "Zm9yZ2Vk", 1);
+    std::fs::write(&path, &tampered).unwrap();
+    let err = audit::verify(&path).await.unwrap_err();
+    assert!(format!("{err:?}").contains("Line 3 follows a record with hash"));
+    assert!(AuditLog::open(&path).await.is_err());
+
+    let reordered: Vec<&str> = contents.lines().rev().collect();
+    std::fs::write(&path, reordered.join("\n") + "\n").unwrap();
+    assert!(audit::verify(&path).await.is_err());
+
+    std::fs::write(&path, &contents[..contents.len() - 10]).unwrap();
+    assert!(audit::verify(&path).await.is_err());
+
+    // A record cut short by a crash is dropped, and the chain carries on from the one before it
+    let audit_log = AuditLog::open(&path).await.unwrap();
+    let head = audit::verify(&path).await.unwrap();
+    assert_eq!(head.records, 2);
+    audit_log
+        .record(
+            &caller,
+            [(&b"not a fingerprint"[..], "test-1:c2lnbmF0dXJlIDQ=")],
+        )
+        .await
+        .unwrap();
+    let head = audit::verify(&path).await.unwrap();
+    assert_eq!(head.records, 3);
+}
+
+#[tokio::test]
+async fn test_transparency_log_proofs() {
+    use crate::transparency::{self, node_hash, Hash, LeafFile, TransparencyLog, Tree};
+
+    /// Fails to sync, like a full disk would, after the write went through.
+    struct UnsyncableFile(tokio::fs::File);
+
+    #[async_trait::async_trait]
+    impl LeafFile for UnsyncableFile {
+        async fn read_to_end(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+            self.0.read_to_end(buf).await
+        }
+
+        async fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+            self.0.write_all(buf).await
+        }
+
+        async fn sync_data(&self) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::StorageFull.into())
+        }
+
+        async fn set_len(&self, size: u64) -> std::io::Result<()> {
+            self.0.set_len(size).await
+        }
+    }
+
+    fn reference_root(leaves: &[Hash]) -> Hash {
+        match leaves.len() {
+            1 => leaves[0],
+            n => {
+                let k = n.next_power_of_two() / 2;
+                node_hash(&reference_root(&leaves[..k]), &reference_root(&leaves[k..]))
+            }
+        }
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc9162#section-2.1.3.2
+    fn verify_inclusion(index: u64, size: u64, leaf: Hash, path: &[Hash], root: Hash) -> bool {
+        let (mut f, mut s, mut r) = (index, size - 1, leaf);
+        for p in path {
+            if s == 0 {
+                return false;
+            }
+            if f & 1 == 1 || f == s {
+                r = node_hash(p, &r);
+                while f & 1 == 0 && f != 0 {
+                    f >>= 1;
+                    s >>= 1;
+                }
+            } else {
+                r = node_hash(&r, p);
+            }
+            f >>= 1;
+            s >>= 1;
+        }
+        s == 0 && r == root
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc9162#section-2.1.4.2
+    fn verify_consistency(first: u64, second: u64, old: Hash, new: Hash, path: &[Hash]) -> bool {
+        let mut path = path.to_vec();
+        if first.is_power_of_two() {
+            path.insert(0, old);
+        }
+        let (mut f, mut s) = (first - 1, second - 1);
+        while f & 1 == 1 {
+            f >>= 1;
+            s >>= 1;
+        }
+        let (mut fr, mut sr) = (path[0], path[0]);
+        for c in &path[1..] {
+            if s == 0 {
+                return false;
+            }
+            if f & 1 == 1 || f == s {
+                fr = node_hash(c, &fr);
+                sr = node_hash(c, &sr);
+                while f & 1 == 0 && f != 0 {
+                    f >>= 1;
+                    s >>= 1;
+                }
+            } else {
+                sr = node_hash(&sr, c);
+            }
+            f >>= 1;
+            s >>= 1;
+        }
+        fr == old && sr == new && s == 0
+    }
+
+    let leaves: Vec<Hash> = (0..20u8).map(|i| transparency::leaf_hash(&[i])).collect();
+    let mut tree = Tree::default();
+    for leaf in &leaves {
+        tree.push(*leaf);
+    }
+    for n in 1..=leaves.len() as u64 {
+        let root = tree.root(0, n);
+        assert_eq!(root, reference_root(&leaves[..n as usize]), "root of {n}");
+
+        for m in 0..n {
+            let path = tree.inclusion_path(m, 0, n);
+            assert!(
+                verify_inclusion(m, n, leaves[m as usize], &path, root),
+                "inclusion of {m} in {n}"
+            );
+            if m != 0 {
+                assert!(!verify_inclusion(m, n, leaves[0], &path, root));
+            }
+        }
+        for m in 1..n {
+            let path = tree.consistency_path(m, 0, n, true);
+            assert!(
+                verify_consistency(m, n, tree.root(0, m), root, &path),
+                "consistency of {m} with {n}"
+            );
+        }
+    }
+
+    // Leaf hashes survive a restart, less anything half written
+    let dir = test_dir("transparency-log");
+    let path = dir.join("log");
+    let log = TransparencyLog::open(&path, SECRET_KEY_FILE_PATH.into())
+        .await
+        .unwrap();
+    let signed = transparency::leaf("test-1:c2lnbmF0dXJl", b"1;/nix/store/...");
+    log.append(vec![b"first".to_vec(), signed.clone(), b"third".to_vec()])
+        .await
+        .unwrap();
+    let (size, root) = log.root().await;
+    drop(log);
+
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, b"partial"))
+        .unwrap();
+    let log = TransparencyLog::open(&path, SECRET_KEY_FILE_PATH.into())
+        .await
+        .unwrap();
+    assert_eq!(log.root().await, (size, root));
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 3 * 32);
+
+    drop(log);
+
+    // A failed append leaves nothing behind for a reopened log to include
+    let file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .append(true)
+        .open(&path)
+        .await
+        .unwrap();
+    let log = TransparencyLog::from_file(UnsyncableFile(file), SECRET_KEY_FILE_PATH.into())
+        .await
+        .unwrap();
+    assert_eq!(log.root().await, (size, root));
+    assert!(log.append(vec![b"unsynced".to_vec()]).await.is_err());
+    assert_eq!(log.root().await, (size, root));
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 3 * 32);
+    drop(log);
+    let log = TransparencyLog::open(&path, SECRET_KEY_FILE_PATH.into())
+        .await
+        .unwrap();
+    assert_eq!(log.root().await, (size, root));
+
+    let proof = log
+        .inclusion_proof_by_hash(&transparency::leaf_hash(&signed), None)
+        .await
+        .unwrap();
+    assert_eq!((proof.leaf_index, proof.tree_size), (1, 3));
+    assert_eq!(proof.audit_path.len(), 2);
+    assert!(log.inclusion_proof(3, None).await.is_err());
+    assert!(log.consistency_proof(0, None).await.is_err());
+    assert!(log.consistency_proof(2, Some(4)).await.is_err());
+    assert_eq!(
+        log.consistency_proof(2, None)
+            .await
+            .unwrap()
+            .consistency_path
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn test_transparency_log_key_file_required_with_malformed_fingerprints() {
+    use clap::Parser;
+
+    use crate::cli::Cli;
+
+    let parse = |args: &[&str]| {
+        let args = [
+            "nixos-cache-signing-server",
+            "--secret-key-file",
+            SECRET_KEY_FILE_PATH,
+        ]
+        .iter()
+        .chain(args);
+        Cli::try_parse_from(args).unwrap().validate()
+    };
+
+    let err = parse(&[
+        "--transparency-log",
+        "log",
+        "--allow-malformed-fingerprints",
+    ])
+    .unwrap_err();
+    assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+    assert!(parse(&[
+        "--transparency-log",
+        "log",
+        "--allow-malformed-fingerprints",
+        "--transparency-log-key-file",
+        "tree-head-key",
+    ])
+    .is_ok());
+    assert!(parse(&["--transparency-log", "log"]).is_ok());
+    assert!(parse(&["--allow-malformed-fingerprints"]).is_ok());
+}
+
+#[tokio::test]
+async fn test_prometheus_metrics() {
+    use crate::error::AppError;
+    use crate::metrics::{metrics, MeasuredPathInfoSource};
+    use crate::nix::PathInfoSource;
+
+    let app = axum::Router::new()
+        .route(
+            "/metrics",
+            axum::routing::get(crate::metrics::serve_metrics),
+        )
+        .route(
+            "/unknown-key",
+            axum::routing::get(|| async {
+                crate::error::Result::<()>::Err(AppError::UnknownKey(String::from("nope")).into())
+            }),
+        )
+        .route("/hang", axum::routing::get(std::future::pending::<()>))
+        .layer(axum::middleware::from_fn(crate::metrics::track));
+
+    // A request given up on before it's answered isn't in flight any more
+    let request = hyper::Request::get("/hang")
+        .body(hyper::Body::empty())
+        .unwrap();
+    let hang = hyper::service::Service::call(&mut app.clone(), request);
+    tokio::time::timeout(std::time::Duration::from_millis(50), hang)
+        .await
+        .expect_err("/hang never answers");
+
+    let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    let get = |path: &'static str| async move {
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        http_get(stream, path, &[]).await.unwrap()
+    };
+
+    metrics().record_signatures("/sign", "metrics-1:c2lnbmF0dXJl\nmetrics-2:c2lnbmF0dXJl");
+    let source = MeasuredPathInfoSource::new(
+        String::from("metrics-test"),
+        Box::new(JsonFile::from_path_infos(vec![test_path_info()])),
+    );
+    assert!(source
+        .require_path_info("/nix/store/00000000000000000000000000000000-missing")
+        .await
+        .is_err());
+    let broken = MeasuredPathInfoSource::new(
+        String::from("metrics-test"),
+        Box::new(crate::nix::NixDb::new(
+            test_dir("metrics").join("missing.sqlite"),
+        )),
+    );
+    assert!(broken
+        .query_path_info(&test_path_info().store_path)
+        .await
+        .is_err());
+
+    assert!(get("/unknown-key").await.starts_with("HTTP/1.1 404"));
+    let response = get("/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    for expected in [
+        r#"cache_signing_server_signatures_total{key="metrics-1",route="/sign"} 1"#,
+        r#"cache_signing_server_signatures_total{key="metrics-2",route="/sign"} 1"#,
+        r#"cache_signing_server_errors_total{error="unknown_key"}"#,
+        r#"cache_signing_server_path_info_duration_seconds_count{backend="metrics-test"} 2"#,
+        // Only the broken database is a failure, the invalid store path isn't
+        r#"cache_signing_server_path_info_failures_total{backend="metrics-test"} 1"#,
+        r#"cache_signing_server_request_duration_seconds_count{route="/unknown-key",method="GET",status="404"} 1"#,
+        // The request for the metrics themselves
+        "cache_signing_server_requests_in_flight 1",
+    ] {
+        assert!(
+            response.contains(expected),
+            "{expected} wasn't in {response}"
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_otlp_trace_export() {
+    use axum::body::Bytes;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::cli::instrumentation::Instrumentation;
+    use crate::cli::otlp::OtlpProtocol;
+
+    // A collector that keeps whatever it's sent
+    let (sender, mut exported) = tokio::sync::mpsc::unbounded_channel::<Bytes>();
+    let collector = axum::Router::new().route(
+        "/v1/traces",
+        axum::routing::post(move |body: Bytes| async move {
+            sender.send(body).unwrap();
+        }),
+    );
+    let server =
+        axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(collector.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    let instrumentation = Instrumentation {
+        otlp_endpoint: Some(format!("http://{addr}/")),
+        otlp_protocol: OtlpProtocol::Http,
+        otlp_service_name: String::from("otlp-test"),
+        otlp_sampling_ratio: 1.0,
+        ..Default::default()
+    };
+    let tracer = instrumentation.otlp_tracer().unwrap().unwrap();
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+    let request = hyper::Request::get("/sign")
+        .header(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+        )
+        .body(hyper::Body::empty())
+        .unwrap();
+    tracing::subscriber::with_default(subscriber, || {
+        drop(crate::trace_layer::trace_layer_make_span_with(&request));
+    });
+    tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider)
+        .await
+        .unwrap();
+
+    let body = exported.recv().await.unwrap();
+    let contains = |needle: &[u8]| body.windows(needle.len()).any(|window| window == needle);
+    // Continues the caller's trace, as the service given
+    assert!(contains(
+        &hex::decode("0af7651916cd43dd8448eb211c80319c").unwrap()
+    ));
+    assert!(contains(&hex::decode("b7ad6b7169203331").unwrap()));
+    assert!(contains(b"otlp-test"));
+    assert!(contains(b"request"));
+}
+
+#[test]
+fn test_trusted_proxy_client_addr() {
+    use axum::http::HeaderMap;
+
+    use crate::client_addr::{parse_trusted_proxy, ClientAddr, ClientAddrHeader, TrustedProxies};
+    use crate::listener::{Peer, PeerAddr};
+
+    let tcp_peer = |addr: &str| Peer {
+        addr: PeerAddr::Tcp(addr.parse().unwrap()),
+        certificate: None,
+    };
+    let headers = |headers: &[(&'static str, &'static str)]| {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, value.parse().unwrap());
+        }
+        map
+    };
+    let ip = |ip: &str| ClientAddr::Ip(ip.parse().unwrap());
+
+    assert!(parse_trusted_proxy("nope").is_err());
+    assert!(parse_trusted_proxy("10.0.0.0/33").is_err());
+    let proxies_setting = |header| {
+        TrustedProxies::new(
+            ["10.1.2.3/8", "127.0.0.1", "fd00::/8"]
+                .into_iter()
+                .map(|proxy| parse_trusted_proxy(proxy).unwrap())
+                .collect(),
+            header,
+        )
+    };
+    let proxies = proxies_setting(ClientAddrHeader::XForwardedFor);
+
+    // Untrusted peers can't claim to be anyone else
+    let spoofed = headers(&[("x-forwarded-for", "198.51.100.7")]);
+    assert_eq!(
+        proxies.resolve(&tcp_peer("203.0.113.9:1234"), &spoofed),
+        ip("203.0.113.9")
+    );
+    assert_eq!(
+        TrustedProxies::default().resolve(&tcp_peer("127.0.0.1:1234"), &spoofed),
+        ip("127.0.0.1")
+    );
+
+    // IPv4 peers on an IPv6 socket are still trusted
+    assert_eq!(
+        proxies.resolve(&tcp_peer("[::ffff:127.0.0.1]:1234"), &spoofed),
+        ip("198.51.100.7")
+    );
+
+    // The first untrusted hop from the right, so a client can't prepend a fake address
+    let chain = headers(&[
+        ("x-forwarded-for", "192.0.2.1, 198.51.100.7"),
+        ("x-forwarded-for", "10.9.9.9"),
+    ]);
+    assert_eq!(
+        proxies.resolve(&tcp_peer("127.0.0.1:1234"), &chain),
+        ip("198.51.100.7")
+    );
+
+    // Only the header the proxies set is read, so a client can't send another they pass along
+    let forwarded = headers(&[
+        (
+            "forwarded",
+            r#"for=192.0.2.1, For="[2001:db8::1]:4711";proto=https"#,
+        ),
+        ("forwarded", "for=10.0.0.1;by=10.0.0.2"),
+        ("x-forwarded-for", "198.51.100.7"),
+        ("x-real-ip", "198.51.100.8"),
+    ]);
+    assert_eq!(
+        proxies.resolve(&tcp_peer("[fd00::1]:1234"), &forwarded),
+        ip("198.51.100.7")
+    );
+    let spoofed_forwarded = headers(&[("forwarded", "for=192.0.2.1")]);
+    assert_eq!(
+        proxies.resolve(&tcp_peer("127.0.0.1:1234"), &spoofed_forwarded),
+        ip("127.0.0.1")
+    );
+    let forwarding_proxies = proxies_setting(ClientAddrHeader::Forwarded);
+    assert_eq!(
+        forwarding_proxies.resolve(&tcp_peer("[fd00::1]:1234"), &forwarded),
+        ip("2001:db8::1")
+    );
+    assert_eq!(
+        proxies_setting(ClientAddrHeader::XRealIp).resolve(&tcp_peer("127.0.0.1:1234"), &forwarded),
+        ip("198.51.100.8")
+    );
+
+    // A hop that can't be read is as far back as we can see
+    let obfuscated = headers(&[("forwarded", "for=_hidden, for=10.0.0.1")]);
+    assert_eq!(
+        forwarding_proxies.resolve(&tcp_peer("127.0.0.1:1234"), &obfuscated),
+        ip("10.0.0.1")
+    );
+    assert_eq!(
+        proxies.resolve(&tcp_peer("127.0.0.1:1234"), &HeaderMap::new()),
+        ip("127.0.0.1")
+    );
+
+    let unix_peer = Peer {
+        addr: PeerAddr::Unix {
+            uid: 1000,
+            gid: 100,
+        },
+        certificate: None,
+    };
+    assert_eq!(proxies.resolve(&unix_peer, &spoofed), ClientAddr::Uid(1000));
+    assert_eq!(ClientAddr::Uid(1000).to_string(), "uid 1000");
+}
+
+#[tokio::test]
+async fn test_rate_limits() {
+    use std::num::NonZeroUsize;
+    use std::time::{Duration, Instant};
+
+    use axum::http::header::RETRY_AFTER;
+    use axum::response::IntoResponse;
+
+    use crate::error::AppError;
+    use crate::listener::{ListenAddr, Listener, Peer};
+    use crate::nix::{PathInfo, PathInfoSource};
+    use crate::rate_limit::{LimitedPathInfoSource, RateLimiter, RateLimits};
+
+    let retry_after = |result: crate::error::Result<()>| match result.unwrap_err().app_error() {
+        Some(AppError::TooManyRequests { retry_after, .. }) => *retry_after,
+        _ => panic!("expected TooManyRequests"),
+    };
+
+    // Two at once, then one every two seconds
+    let limiter = RateLimiter::new(0.5, 2);
+    let start = Instant::now();
+    limiter.check_at("alice", start).unwrap();
+    limiter.check_at("alice", start).unwrap();
+    assert_eq!(retry_after(limiter.check_at("alice", start)), 2);
+    limiter.check_at("bob", start).unwrap();
+    assert_eq!(
+        retry_after(limiter.check_at("alice", start + Duration::from_secs(1))),
+        1
+    );
+    limiter
+        .check_at("alice", start + Duration::from_secs(2))
+        .unwrap();
+    assert!(limiter
+        .check_at("alice", start + Duration::from_secs(2))
+        .is_err());
+
+    // Anonymous callers are only told apart by where they're from, which is limited on its own
+    let mut caller = Caller::anonymous();
+    caller.source = Some(String::from("192.0.2.1"));
+    assert_eq!(caller.rate_limit_key(), None);
+    caller.identity = String::from("builder");
+    assert_eq!(caller.rate_limit_key(), Some("builder"));
+
+    // Requests failing to authenticate are limited too, by where they're from
+    let state = Arc::new(super::AppContextInner {
+        authorizer: Some(Authorizer::from_rules(Vec::new()).unwrap()),
+        rate_limits: Some(RateLimits::new(0.5, 2)),
+        ..test_state().await
+    });
+    let app = axum::Router::new()
+        .route("/publickey", axum::routing::get(|| async { "public key" }))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            crate::rate_limit::limit_caller,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            crate::auth::authorize,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            crate::rate_limit::limit_client_addr,
+        ))
+        .with_state(state.clone())
+        .layer(axum::middleware::from_fn_with_state(
+            state,
+            crate::client_addr::resolve,
+        ));
+    let listener = Listener::bind(&ListenAddr::Tcp(([127, 0, 0, 1], 0).into()), None)
+        .await
+        .unwrap();
+    let [ListenAddr::Tcp(addr)] = *listener.local_addrs() else {
+        unreachable!()
+    };
+    tokio::spawn(
+        axum::Server::builder(listener).serve(app.into_make_service_with_connect_info::<Peer>()),
+    );
+
+    let guess = |token: &'static str| async move {
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let authorization = format!("Bearer {token}");
+        http_get(stream, "/publickey", &[("Authorization", &authorization)])
+            .await
+            .unwrap()
+    };
+    assert!(guess("guess-1").await.starts_with("HTTP/1.1 401"));
+    assert!(guess("guess-2").await.starts_with("HTTP/1.1 401"));
+    let response = guess("guess-3").await;
+    assert!(response.starts_with("HTTP/1.1 429"), "{response}");
+    assert!(response.contains("127.0.0.1 is over the rate limit"));
+
+    let response = crate::error::Report::from(AppError::TooManyRequests {
+        reason: String::from("testing"),
+        retry_after: 3,
+    })
+    .into_response();
+    assert_eq!(response.status(), 429);
+    assert_eq!(response.headers()[RETRY_AFTER], "3");
+
+    // A source that doesn't answer until told to
+    struct Stalled(Arc<tokio::sync::Notify>);
+    #[async_trait::async_trait]
+    impl PathInfoSource for Stalled {
+        async fn query_path_info(
+            &self,
+            _store_path: &str,
+        ) -> crate::error::Result<Option<PathInfo>> {
+            self.0.notified().await;
+            Ok(Some(test_path_info()))
+        }
+    }
+
+    let notify = Arc::new(tokio::sync::Notify::new());
+    let source = Arc::new(LimitedPathInfoSource::new(
+        NonZeroUsize::new(1).unwrap(),
+        Box::new(Stalled(notify.clone())),
+    ));
+    let store_path = test_path_info().store_path;
+    let first = tokio::spawn({
+        let source = source.clone();
+        let store_path = store_path.clone();
+        async move { source.query_path_info(&store_path).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert!(matches!(
+        source
+            .query_path_infos(std::slice::from_ref(&store_path), true)
+            .await
+            .unwrap_err()
+            .app_error(),
+        Some(AppError::TooManyRequests { .. })
+    ));
+    notify.notify_one();
+    assert!(first.await.unwrap().unwrap().is_some());
+    notify.notify_one();
+    assert!(source.query_path_info(&store_path).await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_json_error_responses() {
+    use crate::error::{AppError, ErrorBody};
+
+    let app = axum::Router::new()
+        .route(
+            "/unknown-key",
+            axum::routing::get(|| async {
+                crate::error::Result::<()>::Err(AppError::UnknownKey(String::from("nope")).into())
+            }),
+        )
+        .route(
+            "/secret-key",
+            axum::routing::get(|| async {
+                crate::error::Result::<()>::Err(AppError::MalformedSecretKey.into())
+            }),
+        )
+        .route(
+            "/internal",
+            axum::routing::get(|| async {
+                crate::error::Result::<()>::Err(color_eyre::eyre::eyre!("secret details").into())
+            }),
+        )
+        .layer(axum::middleware::from_fn(crate::error::negotiate))
+        .layer(axum::middleware::from_fn(
+            crate::trace_layer::assign_request_id,
+        ));
+    let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    // The head, lowercased, and the body
+    let get = |path: &'static str, accept: &'static str| async move {
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let response = http_get(stream, path, &[("Accept", accept)]).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.to_lowercase(), body.to_string())
+    };
+    let request_id = |head: &str| {
+        head.lines()
+            .find_map(|line| line.strip_prefix("x-request-id: "))
+            .unwrap()
+            .to_string()
+    };
+
+    let (head, body) = get("/unknown-key", "application/json").await;
+    assert!(head.starts_with("http/1.1 404"));
+    assert!(head.contains("content-type: application/json"));
+    let error: ErrorBody = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+        error,
+        ErrorBody {
+            code: String::from("unknown_key"),
+            message: String::from("No key named 'nope' is loaded"),
+            request_id: Some(request_id(&head)),
+        }
+    );
+    assert_eq!(request_id(&head).len(), 32);
+
+    // Plain text unless JSON is preferred
+    for accept in ["*/*", "text/plain", "application/json;q=0.5, text/*"] {
+        let (head, body) = get("/unknown-key", accept).await;
+        assert!(head.contains("content-type: text/plain"), "{accept}");
+        assert_eq!(body, "No key named 'nope' is loaded");
+    }
+    let (head, _) = get("/unknown-key", "text/plain;q=0.5, application/*").await;
+    assert!(head.contains("content-type: application/json"));
+
+    let (head, body) = get("/secret-key", "text/plain").await;
+    assert!(head.starts_with("http/1.1 500"));
+    assert_eq!(body, "Something went wrong");
+
+    let (head, body) = get("/internal", "application/json").await;
+    assert!(head.starts_with("http/1.1 500"));
+    let error: ErrorBody = serde_json::from_str(&body).unwrap();
+    assert_eq!(error.code, "internal");
+    assert_eq!(error.message, "Something went wrong");
+    assert_eq!(error.request_id, Some(request_id(&head)));
+}
+
+#[tokio::test]
+async fn test_nix_path_info_errors() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+
+    use axum::response::IntoResponse;
+
+    use crate::error::AppError;
+    use crate::nix::command::parse_output;
+    use crate::nix::{NixCommand, PathInfoSource};
+
+    // Paths that aren't there are `None`, like any other backend, but an error in a batch
+    let missing = "/nix/store/00000000000000000000000000000000-missing";
+    assert!(NixCommand.query_path_info(missing).await.unwrap().is_none());
+    let err = NixCommand
+        .query_path_infos(&[String::from(missing)], false)
+        .await
+        .unwrap_err();
+    assert_eq!(err.into_response().status(), 404);
+
+    let hello = test_path_info().store_path;
+    let store_paths = [hello.clone()];
+    let output = |code: i32, stdout: &str, stderr: &str| Output {
+        // A wait status, with the exit code in the second byte
+        status: ExitStatus::from_raw(code << 8),
+        stdout: stdout.as_bytes().to_vec(),
+        stderr: stderr.as_bytes().to_vec(),
+    };
+    let error = |output: Output| {
+        let err = parse_output(&store_paths, &output).unwrap_err();
+        let app_error = err.app_error().map(|err| (err.code(), err.to_string()));
+        (app_error, err.into_response().status())
+    };
+
+    let path_info = format!(
+        r#"[{{"path":"{hello}","narHash":"sha256-sXrPtjqhSoc2u0YfM1HVZThknkSYuRuHdtKCB6wkDFo=","narSize":226552,"references":[]}}]"#
+    );
+    let path_infos =
+        parse_output(&store_paths, &output(0, &path_info, "warning: ignored")).unwrap();
+    assert_eq!(path_infos[0].store_path, hello);
+
+    let (app_error, status) = error(output(
+        1,
+        "",
+        &format!("error: path '{hello}' is not valid\n"),
+    ));
+    assert_eq!(
+        app_error,
+        Some((
+            "store_path_not_valid",
+            format!("Store path '{hello}' is not valid in the Nix store")
+        ))
+    );
+    assert_eq!(status, 404);
+
+    let (app_error, status) = error(output(
+        1,
+        "",
+        "error: cannot connect to socket at '/nix/var/nix/daemon-socket/socket': Connection refused\n",
+    ));
+    assert_eq!(app_error.unwrap().0, "nix_unavailable");
+    assert_eq!(status, 503);
+
+    let (app_error, status) = error(output(1, "", "error: experimental Nix feature\n"));
+    assert_eq!(
+        app_error,
+        Some((
+            "nix_command_failed",
+            String::from("`nix path-info` failed with exit status: 1")
+        ))
+    );
+    assert_eq!(status, 502);
+
+    // Stderr is only logged, as the source of the error
+    let err = parse_output(
+        &store_paths,
+        &output(1, "", "warning: something\nerror: something else\n"),
+    )
+    .unwrap_err();
+    let logged = format!("{err:?}");
+    assert!(logged.contains("Stderr:"), "{logged}");
+    assert!(logged.contains("warning: something\n"), "{logged}");
+    assert!(logged.contains("error: something else"), "{logged}");
+    assert!(!err.app_error().unwrap().to_string().contains("something"));
+
+    let (app_error, status) = error(output(0, "not json", ""));
+    assert_eq!(app_error.unwrap().0, "invalid_nix_output");
+    assert_eq!(status, 502);
+
+    let (app_error, status) = error(output(0, "[]", ""));
+    assert_eq!(app_error.unwrap().0, "no_path_info");
+    assert_eq!(status, 502);
+
+    // Nix can't sign a hash it can't print
+    let sha384 = format!(
+        r#"[{{"path":"{hello}","narHash":"sha384-OLBgp1GsljhM2TJ+sbHjaiH9txEUvgdDTAzHv2P24donTt6/529l+9Ua0vFImLlb","narSize":1,"references":[]}}]"#
+    );
+    let path_infos = parse_output(&store_paths, &output(0, &sha384, "")).unwrap();
+    let err = path_infos[0].fingerprint().unwrap_err();
+    assert!(matches!(
+        err.app_error(),
+        Some(AppError::UnsupportedHashAlgorithm(algorithm)) if algorithm == "sha384"
+    ));
+    assert_eq!(err.into_response().status(), 422);
+
+    let err = crate::error::Report::from(AppError::NixUnavailable(String::from("testing")));
+    assert_eq!(err.into_response().status(), 503);
+}
@@ -1,27 +1,71 @@
 use axum::body::BoxBody;
 use axum::extract::ConnectInfo;
+use axum::http::HeaderValue;
+use axum::middleware::Next;
 use axum::response::Response;
 use hyper::{Body, Request};
-use std::net::SocketAddr;
+use opentelemetry_http::HeaderExtractor;
 use std::time::Duration;
 use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::client_addr::ClientAddr;
+use crate::listener::Peer;
+
+/// A random ID for each request, in its span, its `X-Request-Id` response header and any JSON
+/// error body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+const X_REQUEST_ID: &str = "x-request-id";
+
+/// Give the request an ID before its span is made, and tell the caller it.
+pub(crate) async fn assign_request_id<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let mut id = [0; 16];
+    dryoc::rng::copy_randombytes(&mut id);
+    let request_id = hex::encode(id);
+
+    request
+        .extensions_mut()
+        .insert(RequestId(request_id.clone()));
+    let mut response = next.run(request).await;
+    if let Ok(request_id) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(X_REQUEST_ID, request_id);
+    }
+
+    response
+}
 
 pub(crate) fn trace_layer_make_span_with(request: &Request<Body>) -> Span {
-    tracing::error_span!("request",
+    let span = tracing::error_span!("request",
+        request_id = request.extensions()
+            .get::<RequestId>()
+            .map_or("<none>", |RequestId(id)| id.as_str()),
         uri = %request.uri(),
         method = %request.method(),
-        // FIXME: doesn't handle X-forwarded-for and friends
         source = request.extensions()
-            .get::<ConnectInfo<SocketAddr>>()
-            .map(|connect_info|
-                tracing::field::display(connect_info.ip().to_string()),
-            ).unwrap_or_else(||
-                tracing::field::display(String::from("<unknown>"))
+            .get::<ClientAddr>()
+            .map(ClientAddr::to_string)
+            .or_else(|| request.extensions()
+                .get::<ConnectInfo<Peer>>()
+                .map(|connect_info| connect_info.source())
+            ).map_or_else(||
+                tracing::field::display(String::from("<unknown>")),
+                tracing::field::display,
             ),
         // Fields must be defined to be used, define them as empty if they populate later
+        identity = tracing::field::Empty,
         status = tracing::field::Empty,
         latency = tracing::field::Empty,
-    )
+    );
+
+    // Does nothing unless traces are exported over OTLP
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    span.set_parent(parent);
+
+    span
 }
 
 pub(crate) fn trace_layer_on_request(_request: &Request<Body>, _span: &Span) {
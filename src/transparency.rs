@@ -0,0 +1,399 @@
+//! A Merkle tree transparency log of every signature, hashed as in RFC 9162.
+//!
+//! Each leaf is `<key name>:<base64 signature>\n<fingerprint>`, so anyone holding a signature
+//! can check it was logged. Only the leaf hashes are kept, 32 bytes each, in an append-only file.
+// Adapted from:
+// https://www.rfc-editor.org/rfc/rfc9162#section-2.1
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use color_eyre::eyre::WrapErr;
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::error::{AppError, Result};
+
+pub type Hash = [u8; 32];
+
+/// A signed statement of the log's size and root hash.
+///
+/// The signature is made over [`TreeHead::signed_data`], in the same `name:base64sig` form as
+/// store path signatures.
+#[derive(Debug, serde_derive::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TreeHead {
+    pub tree_size: u64,
+    /// Base64
+    pub root_hash: String,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub signature: String,
+}
+
+impl TreeHead {
+    /// What the tree head signature is made over, which can't be mistaken for a fingerprint.
+    pub fn signed_data(tree_size: u64, root_hash: &str, timestamp: u64) -> String {
+        format!("nixos-cache-signing-server tree head\n{tree_size}\n{root_hash}\n{timestamp}\n")
+    }
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InclusionProof {
+    pub leaf_index: u64,
+    pub tree_size: u64,
+    /// Base64 hashes, from the leaf up
+    pub audit_path: Vec<String>,
+}
+
+#[derive(Debug, serde_derive::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsistencyProof {
+    pub first: u64,
+    pub second: u64,
+    /// Base64 hashes
+    pub consistency_path: Vec<String>,
+}
+
+/// The file leaf hashes are kept in, so tests can make it fail.
+#[async_trait::async_trait]
+pub trait LeafFile: Send {
+    async fn read_to_end(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize>;
+    async fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()>;
+    async fn sync_data(&self) -> std::io::Result<()>;
+    async fn set_len(&self, size: u64) -> std::io::Result<()>;
+}
+
+#[async_trait::async_trait]
+impl LeafFile for tokio::fs::File {
+    async fn read_to_end(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        tokio::io::AsyncReadExt::read_to_end(self, buf).await
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        tokio::io::AsyncWriteExt::write_all(self, buf).await
+    }
+
+    async fn sync_data(&self) -> std::io::Result<()> {
+        tokio::fs::File::sync_data(self).await
+    }
+
+    async fn set_len(&self, size: u64) -> std::io::Result<()> {
+        tokio::fs::File::set_len(self, size).await
+    }
+}
+
+pub struct TransparencyLog<F = tokio::fs::File> {
+    file: Mutex<F>,
+    tree: RwLock<Tree>,
+    /// The secret key file tree heads are signed with
+    pub secret_key_path: PathBuf,
+}
+
+impl TransparencyLog {
+    /// Open the log, dropping any partly written hash at the end from a crash.
+    #[tracing::instrument]
+    pub async fn open(path: &Path, secret_key_path: PathBuf) -> Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)
+            .await
+            .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+
+        Self::from_file(file, secret_key_path).await
+    }
+}
+
+impl<F: LeafFile> TransparencyLog<F> {
+    /// Read the log from an open file, dropping any partly written hash at the end.
+    pub async fn from_file(mut file: F, secret_key_path: PathBuf) -> Result<Self> {
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)
+            .await
+            .wrap_err("Failed to read the transparency log")?;
+
+        let partial = contents.len() % 32;
+        if partial != 0 {
+            // It was never synced, so it can't be in a tree head anyone has seen
+            tracing::warn!("dropping {partial} bytes of a partly written leaf hash");
+            file.set_len((contents.len() - partial) as u64)
+                .await
+                .wrap_err("Failed to truncate the transparency log")?;
+        }
+
+        let mut tree = Tree::default();
+        for leaf_hash in contents.chunks_exact(32) {
+            tree.push(leaf_hash.try_into()?);
+        }
+        tracing::debug!("transparency log has {} leaves", tree.size());
+
+        Ok(Self {
+            file: Mutex::new(file),
+            tree: RwLock::new(tree),
+            secret_key_path,
+        })
+    }
+
+    /// Append leaves, only returning once they're on disk.
+    pub async fn append(&self, leaves: Vec<Vec<u8>>) -> Result<()> {
+        let leaf_hashes: Vec<Hash> = leaves.iter().map(|leaf| leaf_hash(leaf)).collect();
+
+        // Held until the tree is updated, so the file and tree stay in the same order
+        let mut file = self.file.lock().await;
+        let len = self.tree.read().await.size() * 32;
+        if let Err(err) = Self::write(&mut file, &leaf_hashes.concat()).await {
+            // Leaves no tree head includes would make the log inconsistent with those already
+            // served once it's reopened
+            if let Err(err) = file.set_len(len).await {
+                tracing::error!(
+                    "failed to truncate the transparency log after a failed write: {err}"
+                );
+            }
+            return Err(err);
+        }
+
+        let mut tree = self.tree.write().await;
+        for leaf_hash in leaf_hashes {
+            tree.push(leaf_hash);
+        }
+
+        Ok(())
+    }
+
+    async fn write(file: &mut F, leaf_hashes: &[u8]) -> Result<()> {
+        file.write_all(leaf_hashes)
+            .await
+            .wrap_err("Failed to write to the transparency log")?;
+        file.sync_data()
+            .await
+            .wrap_err("Failed to sync the transparency log")?;
+
+        Ok(())
+    }
+
+    /// The current size and root hash.
+    pub async fn root(&self) -> (u64, Hash) {
+        let tree = self.tree.read().await;
+        (tree.size(), tree.root(0, tree.size()))
+    }
+
+    /// Prove the leaf with `leaf_hash` is in the tree of `tree_size`, defaulting to the current
+    /// size.
+    pub async fn inclusion_proof_by_hash(
+        &self,
+        leaf_hash: &Hash,
+        tree_size: Option<u64>,
+    ) -> Result<InclusionProof> {
+        let leaf_index = *self
+            .tree
+            .read()
+            .await
+            .indices
+            .get(leaf_hash)
+            .ok_or_else(|| AppError::LogEntryNotFound(STANDARD.encode(leaf_hash)))?;
+
+        self.inclusion_proof(leaf_index, tree_size).await
+    }
+
+    pub async fn inclusion_proof(
+        &self,
+        leaf_index: u64,
+        tree_size: Option<u64>,
+    ) -> Result<InclusionProof> {
+        let tree = self.tree.read().await;
+        let tree_size = tree_size.unwrap_or(tree.size());
+        if tree_size > tree.size() {
+            return Err(AppError::InvalidLogQuery(format!(
+                "the tree only has {} leaves, not {tree_size}",
+                tree.size()
+            ))
+            .into());
+        }
+        if leaf_index >= tree_size {
+            return Err(AppError::InvalidLogQuery(format!(
+                "leaf {leaf_index} isn't in a tree of {tree_size} leaves"
+            ))
+            .into());
+        }
+
+        Ok(InclusionProof {
+            leaf_index,
+            tree_size,
+            audit_path: encode_hashes(tree.inclusion_path(leaf_index, 0, tree_size)),
+        })
+    }
+
+    /// Prove the tree of `first` leaves is a prefix of the tree of `second`, defaulting to the
+    /// current size.
+    pub async fn consistency_proof(
+        &self,
+        first: u64,
+        second: Option<u64>,
+    ) -> Result<ConsistencyProof> {
+        let tree = self.tree.read().await;
+        let second = second.unwrap_or(tree.size());
+        if second > tree.size() {
+            return Err(AppError::InvalidLogQuery(format!(
+                "the tree only has {} leaves, not {second}",
+                tree.size()
+            ))
+            .into());
+        }
+        if first == 0 || first > second {
+            return Err(AppError::InvalidLogQuery(format!(
+                "can't prove a tree of {first} leaves is a prefix of one of {second}"
+            ))
+            .into());
+        }
+
+        let consistency_path = if first == second {
+            Vec::new()
+        } else {
+            tree.consistency_path(first, 0, second, true)
+        };
+
+        Ok(ConsistencyProof {
+            first,
+            second,
+            consistency_path: encode_hashes(consistency_path),
+        })
+    }
+}
+
+/// What's logged for one signature, `signature` being in `name:base64sig` form.
+pub fn leaf(signature: &str, fingerprint: &[u8]) -> Vec<u8> {
+    [signature.as_bytes(), b"\n", fingerprint].concat()
+}
+
+pub fn leaf_hash(leaf: &[u8]) -> Hash {
+    Sha256::new()
+        .chain_update([0])
+        .chain_update(leaf)
+        .finalize()
+        .into()
+}
+
+pub fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new()
+        .chain_update([1])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+fn encode_hashes(hashes: Vec<Hash>) -> Vec<String> {
+    hashes.iter().map(|hash| STANDARD.encode(hash)).collect()
+}
+
+/// The largest power of two smaller than `n`, which is where RFC 9162 splits a tree of `n`.
+fn split(n: u64) -> u64 {
+    1 << (63 - (n - 1).leading_zeros())
+}
+
+/// Every leaf hash, and the hash of every complete subtree so they're only computed once.
+#[derive(Default)]
+pub struct Tree {
+    /// `levels[l][i]` is the hash of leaves `i * 2^l` up to `(i + 1) * 2^l`
+    levels: Vec<Vec<Hash>>,
+    /// The first index of each leaf hash
+    indices: HashMap<Hash, u64>,
+}
+
+impl Tree {
+    pub fn size(&self) -> u64 {
+        self.levels.first().map_or(0, |leaves| leaves.len() as u64)
+    }
+
+    pub fn push(&mut self, leaf_hash: Hash) {
+        let index = self.size();
+        self.indices.entry(leaf_hash).or_insert(index);
+
+        let mut hash = leaf_hash;
+        for level in 0.. {
+            if self.levels.len() == level {
+                self.levels.push(Vec::new());
+            }
+            self.levels[level].push(hash);
+
+            let nodes = &self.levels[level];
+            if nodes.len() % 2 == 1 {
+                break;
+            }
+            hash = node_hash(&nodes[nodes.len() - 2], &nodes[nodes.len() - 1]);
+        }
+    }
+
+    /// The hash of leaves `start..end`, where `start` is a multiple of the largest power of two
+    /// no bigger than `end - start`, as it is for every subtree RFC 9162 asks for.
+    pub fn root(&self, start: u64, end: u64) -> Hash {
+        let n = end - start;
+        if n == 0 {
+            return Sha256::digest([]).into();
+        }
+        if n.is_power_of_two() {
+            return self.levels[n.trailing_zeros() as usize][(start / n) as usize];
+        }
+
+        let k = split(n);
+        node_hash(&self.root(start, start + k), &self.root(start + k, end))
+    }
+
+    /// `PATH(m, D[start:end])`
+    pub fn inclusion_path(&self, m: u64, start: u64, end: u64) -> Vec<Hash> {
+        let n = end - start;
+        if n == 1 {
+            return Vec::new();
+        }
+
+        let k = split(n);
+        let (mut path, sibling) = if m < k {
+            (
+                self.inclusion_path(m, start, start + k),
+                self.root(start + k, end),
+            )
+        } else {
+            (
+                self.inclusion_path(m - k, start + k, end),
+                self.root(start, start + k),
+            )
+        };
+        path.push(sibling);
+
+        path
+    }
+
+    /// `SUBPROOF(m, D[start:end], complete)`
+    pub fn consistency_path(&self, m: u64, start: u64, end: u64, complete: bool) -> Vec<Hash> {
+        let n = end - start;
+        if m == n {
+            return if complete {
+                Vec::new()
+            } else {
+                vec![self.root(start, end)]
+            };
+        }
+
+        let k = split(n);
+        let (mut path, sibling) = if m <= k {
+            (
+                self.consistency_path(m, start, start + k, complete),
+                self.root(start + k, end),
+            )
+        } else {
+            (
+                self.consistency_path(m - k, start + k, end, false),
+                self.root(start, start + k),
+            )
+        };
+        path.push(sibling);
+
+        path
+    }
+}